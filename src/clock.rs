@@ -0,0 +1,59 @@
+use std::{fmt::Debug, sync::Mutex};
+
+use chrono::prelude::*;
+use tokio::time::Instant;
+
+///
+/// Clock
+///   source of the current time for loopers, workers and holders
+///
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+///
+/// SystemClock
+///   the time of the system, Utc::now()
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+///
+/// MockClock
+///   starts at the given time and advances with the tokio clock,
+///   so with tokio::time::pause the sleeps of the loops finish without real waiting
+///   and the time is exactly what was scheduled
+///
+#[derive(Debug)]
+pub struct MockClock {
+    base: Mutex<(DateTime<Utc>, Instant)>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            base: Mutex::new((now, Instant::now())),
+        }
+    }
+
+    ///
+    /// Jumps to the given time without advancing the tokio clock
+    /// e.g. to simulate a suspended process or a clock adjustment
+    ///
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.base.lock().unwrap_or_else(|err| err.into_inner()) = (now, Instant::now());
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        let (base, started) = *self.base.lock().unwrap_or_else(|err| err.into_inner());
+        base + started.elapsed()
+    }
+}
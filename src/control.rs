@@ -0,0 +1,160 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+};
+use tracing::info;
+
+use crate::status::{find_control, lock, TaskStatus};
+
+// 一時停止と手動実行の指示
+pub(crate) struct Control {
+    paused: watch::Sender<bool>,
+    trigger: Notify,
+}
+
+impl Control {
+    pub(crate) fn new() -> Self {
+        Self {
+            paused: watch::channel(false).0,
+            trigger: Notify::new(),
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // 再開されるまで待つ
+    pub(crate) async fn resumed(&self) {
+        let mut receiver = self.paused.subscribe();
+        let _ = receiver.wait_for(|paused| !paused).await;
+    }
+
+    // trigger_nowされるまで待つ、実行中に呼ばれた分は次に待った時にすぐ戻る
+    pub(crate) async fn triggered(&self) {
+        self.trigger.notified().await
+    }
+}
+
+///
+/// TaskControl
+///   handle to control a looper or worker while it is running
+///   pause: no new execution starts until resume, the running one is not interrupted
+///     a paused task still stops as soon as the token is cancelled
+///   resume: executions start again, ticks of a looper missed while paused follow MisfirePolicy
+///   trigger_now: executes once as soon as possible, also while paused
+///   status: current status of the task
+///
+#[derive(Clone)]
+pub struct TaskControl {
+    status: Arc<Mutex<TaskStatus>>,
+    control: Arc<Control>,
+}
+
+impl TaskControl {
+    pub(crate) fn new(status: Arc<Mutex<TaskStatus>>, control: Arc<Control>) -> Self {
+        Self { status, control }
+    }
+
+    pub fn pause(&self) {
+        let mut status = lock(&self.status);
+        status.paused = true;
+        self.control.paused.send_replace(true);
+        info!(task = status.name, "task paused");
+    }
+
+    pub fn resume(&self) {
+        let mut status = lock(&self.status);
+        status.paused = false;
+        self.control.paused.send_replace(false);
+        info!(task = status.name, "task resumed");
+    }
+
+    pub fn trigger_now(&self) {
+        info!(task = lock(&self.status).name, "task triggered");
+        self.control.trigger.notify_one();
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        lock(&self.status).clone()
+    }
+}
+
+///
+/// Returns the control of the task made by make_looper or make_worker
+///   e.g. `let control = task_control(&handle).unwrap();`
+///   None when the task was made otherwise or has already finished
+///
+pub fn task_control(handle: &JoinHandle<()>) -> Option<TaskControl> {
+    find_control(handle.id())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use chrono::prelude::*;
+    use cron::Schedule;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{clock::MockClock, make_looper_with_config, LoopConfig, LoopState};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task_control() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let started = Arc::new(Mutex::new(vec![]));
+        let task_started = started.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("0 0 * * * *")?,
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_started
+                    .lock()
+                    .unwrap()
+                    .push(context.started.timestamp());
+                async { LoopState::Continue }
+            },
+            |_| async {},
+        );
+        let control = task_control(&handle).unwrap();
+
+        // 手動実行しても以降のスケジュールは変わらない
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        control.trigger_now();
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(*started.lock().unwrap(), vec![60, 3600]);
+
+        // 一時停止中はスケジュールでは動かず、手動実行だけ動く
+        control.pause();
+        assert!(control.status().paused);
+        tokio::time::sleep(Duration::from_secs(2 * 3600)).await;
+        control.trigger_now();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*started.lock().unwrap(), vec![60, 3600, 10860]);
+
+        // 一時停止中に過ぎた時刻はMisfirePolicy::Skipで実行せず、次の時刻から動く
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(*started.lock().unwrap(), vec![60, 3600, 10860]);
+        control.resume();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*started.lock().unwrap(), vec![60, 3600, 10860]);
+        assert!(!control.status().paused);
+        tokio::time::sleep(Duration::from_secs(3480)).await;
+        assert_eq!(*started.lock().unwrap(), vec![60, 3600, 10860, 18000]);
+
+        // 一時停止中もキャンセルで止まる
+        control.pause();
+        token.cancel();
+        handle.await?;
+        Ok(())
+    }
+}
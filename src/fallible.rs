@@ -0,0 +1,236 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use cron::Schedule;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+
+use crate::{
+    make_looper_with_config, make_worker_with_config, resource::Resource, LoopConfig, LoopState,
+    TaskContext,
+};
+
+///
+/// ErrorPolicy
+///   Continue: log the error and continue as LoopState::Continue
+///   Backoff: log the error and continue as LoopState::Backoff
+///   TerminateAfter(n): back off as above, terminate after n consecutive errors
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Continue,
+    Backoff,
+    TerminateAfter(u64),
+}
+
+type ErrorCallback<E> = Arc<dyn Fn(&TaskContext, &E) + Send + Sync>;
+
+///
+/// ErrorHandler
+///   policy: what to do next when the task returns Err
+///   callback: called with each error after it is logged, e.g. to report it
+///
+pub struct ErrorHandler<E> {
+    pub policy: ErrorPolicy,
+    pub callback: Option<ErrorCallback<E>>,
+}
+
+impl<E> ErrorHandler<E> {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            callback: None,
+        }
+    }
+
+    pub fn on_error(mut self, callback: impl Fn(&TaskContext, &E) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl<E> Default for ErrorHandler<E> {
+    fn default() -> Self {
+        Self::new(ErrorPolicy::Backoff)
+    }
+}
+
+impl<E> Clone for ErrorHandler<E> {
+    fn clone(&self) -> Self {
+        Self {
+            policy: self.policy,
+            callback: self.callback.clone(),
+        }
+    }
+}
+
+impl<E: Display> ErrorHandler<E> {
+    // 実行結果をLoopStateにする、失敗の連続回数はOkで戻す
+    fn handle(
+        &self,
+        context: &TaskContext,
+        res: Result<LoopState, E>,
+        failures: &AtomicU64,
+    ) -> LoopState {
+        let err = match res {
+            Ok(state) => {
+                failures.store(0, Ordering::SeqCst);
+                return state;
+            }
+            Err(err) => err,
+        };
+        let failures = failures.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!(error = %err, failures, scheduled = %context.scheduled, "task error");
+        if let Some(callback) = &self.callback {
+            callback(context, &err);
+        }
+        match self.policy {
+            ErrorPolicy::Continue => LoopState::Continue,
+            ErrorPolicy::Backoff => LoopState::Backoff,
+            ErrorPolicy::TerminateAfter(max) if failures >= max => {
+                error!(failures, "terminate after consecutive errors");
+                LoopState::Terminate
+            }
+            ErrorPolicy::TerminateAfter(_) => LoopState::Backoff,
+        }
+    }
+}
+
+///
+/// make_looper_with_config whose task returns Result<LoopState, E>,
+/// errors are logged and handled by error_handler
+///
+#[allow(clippy::too_many_arguments)]
+pub fn make_fallible_looper_with_config<R, E, Fut1, Fut2>(
+    resource: R,
+    token: CancellationToken,
+    schedule: Schedule,
+    stop_check_duration: impl Into<Option<Duration>>,
+    config: LoopConfig,
+    error_handler: ErrorHandler<E>,
+    task_function: impl Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    stop_function: impl Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    E: Display + Send + 'static,
+    Fut1: Future<Output = Result<LoopState, E>> + Send + 'static,
+    Fut2: Future<Output = ()> + Send,
+{
+    let failures = Arc::new(AtomicU64::new(0));
+    make_looper_with_config(
+        resource,
+        token,
+        schedule,
+        stop_check_duration,
+        config,
+        move |context, resource, token| {
+            let future = task_function(context.clone(), resource, token);
+            let error_handler = error_handler.clone();
+            let failures = failures.clone();
+            async move { error_handler.handle(&context, future.await, &failures) }
+        },
+        stop_function,
+    )
+}
+
+///
+/// make_worker_with_config whose task returns Result<LoopState, E>,
+/// errors are logged and handled by error_handler
+///
+pub fn make_fallible_worker_with_config<R, E, Fut1, Fut2>(
+    resource: R,
+    token: CancellationToken,
+    stop_check_duration: impl Into<Option<Duration>>,
+    config: LoopConfig,
+    error_handler: ErrorHandler<E>,
+    task_function: impl Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    stop_function: impl Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    E: Display + Send + 'static,
+    Fut1: Future<Output = Result<LoopState, E>> + Send,
+    Fut2: Future<Output = ()> + Send,
+{
+    let failures = Arc::new(AtomicU64::new(0));
+    make_worker_with_config(
+        resource,
+        token,
+        stop_check_duration,
+        config,
+        move |context, resource, token| {
+            let future = task_function(context.clone(), resource, token);
+            let error_handler = error_handler.clone();
+            let failures = failures.clone();
+            async move { error_handler.handle(&context, future.await, &failures) }
+        },
+        stop_function,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chrono::prelude::*;
+
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        status::{find_status, lock, ExitReason},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fallible_worker() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let callback_errors = errors.clone();
+        let results = Arc::new(Mutex::new(vec![
+            Err("a"),
+            Ok(LoopState::Continue),
+            Err("b"),
+            Err("c"),
+            Err("d"),
+            Ok(LoopState::Continue),
+        ]));
+        let handle = make_fallible_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            ErrorHandler::new(ErrorPolicy::TerminateAfter(3)).on_error(move |context, err| {
+                callback_errors
+                    .lock()
+                    .unwrap()
+                    .push((context.started.timestamp(), *err));
+            }),
+            move |_, _, _| {
+                let res = results.lock().unwrap().remove(0);
+                async move { res }
+            },
+            |_| async {},
+        );
+        let status = find_status(handle.id()).unwrap();
+        handle.await?;
+
+        // 成功で連続回数が戻り、3回続けて失敗したら終了する
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![(0, "a"), (1, "b"), (2, "c"), (4, "d")]
+        );
+        assert_eq!(lock(&status).exit, Some(ExitReason::Terminated));
+        Ok(())
+    }
+}
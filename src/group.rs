@@ -0,0 +1,91 @@
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+///
+/// TaskGroup
+///   named set of tasks sharing a child token of the parent token
+///   cancelling the parent stops every group made from it, cancelling the group,
+///   e.g. by LoopState::AllTerminate of a task given token(), stops only the tasks
+///   of the group and its subgroups
+///
+#[derive(Debug, Clone)]
+pub struct TaskGroup {
+    name: String,
+    token: CancellationToken,
+}
+
+impl TaskGroup {
+    pub fn new(name: impl Into<String>, parent: &CancellationToken) -> Self {
+        Self {
+            name: name.into(),
+            token: parent.child_token(),
+        }
+    }
+
+    ///
+    /// Subgroup stopped with this group, named "{group}/{name}"
+    ///
+    pub fn group(&self, name: &str) -> Self {
+        Self::new(format!("{}/{}", self.name, name), &self.token)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///
+    /// Token to give the tasks of the group
+    ///
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn cancel(&self) {
+        info!(group = self.name, "cancel group");
+        self.token.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{make_worker, LoopState};
+
+    #[tokio::test]
+    async fn test_task_group() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let redis = TaskGroup::new("redis", &token);
+        let subscriber = redis.group("subscriber");
+        let db = TaskGroup::new("db", &token);
+        assert_eq!(subscriber.name(), "redis/subscriber");
+
+        // グループ内のAllTerminateはそのグループと下位のグループだけ止める
+        let terminate = make_worker(
+            redis.token(),
+            None,
+            |_| async { LoopState::AllTerminate },
+            || async {},
+        );
+        let idle = |_| async { LoopState::Duration(Duration::from_secs(60)) };
+        let sub = make_worker(subscriber.token(), None, idle, || async {});
+        let other = make_worker(db.token(), None, idle, || async {});
+        terminate.await?;
+        sub.await?;
+        assert!(redis.is_cancelled());
+        assert!(!db.is_cancelled());
+        assert!(!token.is_cancelled());
+        assert!(!other.is_finished());
+
+        // 親のキャンセルは全てのグループを止める
+        token.cancel();
+        other.await?;
+        assert!(db.is_cancelled());
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use std::{fmt::Write as _, net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::prelude::*;
+use futures_util::future::BoxFuture;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    resource::Resource,
+    status::{task_statuses, ExitReason, TaskStatus},
+};
+
+///
+/// HealthConfig
+///   interval_multiple: a task is unhealthy when no execution finished within
+///     this multiple of the interval until its next tick
+///   min_timeout: lower bound of the time above, for workers which run continuously
+///   clock: source of the current time
+///
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    pub interval_multiple: u32,
+    pub min_timeout: Duration,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval_multiple: 3,
+            min_timeout: Duration::from_secs(60),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+// リクエストを読み終えるまでの上限
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ReadyCheck = Box<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync>;
+
+///
+/// Health
+///   /healthz: 200 when every looper and worker is healthy, otherwise 503
+///   /readyz: /healthz and every resource added by ready_check can be acquired
+///
+pub struct Health {
+    config: HealthConfig,
+    ready_checks: Vec<(String, ReadyCheck)>,
+}
+
+impl Health {
+    pub fn new(config: HealthConfig) -> Self {
+        Self {
+            config,
+            ready_checks: vec![],
+        }
+    }
+
+    ///
+    /// Adds a resource checked by /readyz, e.g. the pool given to make_worker
+    ///
+    pub fn ready_check<R: Resource + Clone>(
+        mut self,
+        name: impl Into<String>,
+        resource: R,
+    ) -> Self {
+        self.ready_checks.push((
+            name.into(),
+            Box::new(move || {
+                let resource = resource.clone();
+                Box::pin(async move { resource.ping().await })
+            }),
+        ));
+        self
+    }
+
+    ///
+    /// Returns the problems of the tasks, empty when all of them are healthy
+    ///
+    pub fn liveness(&self) -> Vec<String> {
+        let now = self.config.clock.now();
+        task_statuses()
+            .iter()
+            .filter_map(|status| self.check_task(status, &now))
+            .collect()
+    }
+
+    ///
+    /// Returns the problems of the tasks and the resources, empty when ready
+    ///
+    pub async fn readiness(&self) -> Vec<String> {
+        let mut problems = self.liveness();
+        for (name, check) in &self.ready_checks {
+            if !check().await {
+                problems.push(format!("{}: unavailable", name));
+            }
+        }
+        problems
+    }
+
+    ///
+    /// Serves /healthz and /readyz until the token is cancelled
+    ///
+    pub async fn serve(
+        self,
+        addr: SocketAddr,
+        token: CancellationToken,
+    ) -> std::io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr).await?;
+        debug!(addr = %listener.local_addr()?, "health server started");
+        let health = Arc::new(self);
+        Ok(spawn(async move {
+            loop {
+                let (stream, _) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            warn!(error = %err, "health accept error");
+                            continue;
+                        }
+                    },
+                    _ = token.cancelled() => break,
+                };
+                let health = health.clone();
+                spawn(async move {
+                    if let Err(err) = health.respond(stream).await {
+                        debug!(error = %err, "health response error");
+                    }
+                });
+            }
+        }))
+    }
+
+    // 最後に終わった実行から、次の実行までの間隔の倍数を超えていれば異常
+    fn check_task(&self, status: &TaskStatus, now: &DateTime<Utc>) -> Option<String> {
+        let name = &status.name;
+        if status.exit == Some(ExitReason::Panicked) {
+            return Some(format!("{}: stopped by panic", name));
+        }
+        // 終了したものと一時停止中のものは実行されなくても正常
+        if status.exit.is_some() || status.paused {
+            return None;
+        }
+        let heartbeat = status.last_finished.unwrap_or(status.registered);
+        let interval = status
+            .next_tick
+            .and_then(|next_tick| (next_tick - heartbeat).to_std().ok())
+            .unwrap_or_default();
+        let timeout = interval
+            .saturating_mul(self.config.interval_multiple)
+            .max(self.config.min_timeout);
+        let elapsed = (*now - heartbeat).to_std().unwrap_or_default();
+        (elapsed > timeout)
+            .then(|| format!("{}: no execution finished for {}s", name, elapsed.as_secs()))
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        // リクエストラインとヘッダーだけ読む、送ってこない接続は切る
+        let mut buf = vec![0; 4096];
+        let mut len = 0;
+        timeout(READ_TIMEOUT, async {
+            while len < buf.len() && !buf[..len].windows(4).any(|it| it == b"\r\n\r\n") {
+                let n = stream.read(&mut buf[len..]).await?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .await??;
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+
+        let (code, body) = match path {
+            "/healthz" => result(self.liveness()),
+            "/readyz" => result(self.readiness().await),
+            _ => ("404 Not Found", "not found\n".to_owned()),
+        };
+        let mut response = String::new();
+        let _ = write!(
+            response,
+            "HTTP/1.1 {}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            code,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+fn result(problems: Vec<String>) -> (&'static str, String) {
+    if problems.is_empty() {
+        ("200 OK", "ok\n".to_owned())
+    } else {
+        (
+            "503 Service Unavailable",
+            problems.iter().map(|it| format!("{}\n", it)).collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_worker, LoopState};
+
+    #[tokio::test]
+    async fn test_health() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let handle = make_worker(
+            token.clone(),
+            None,
+            |_| async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                LoopState::Continue
+            },
+            || async {},
+        );
+        let health = Health::new(HealthConfig {
+            min_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let addr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            listener.local_addr()?
+        };
+        let server = health.serve(addr, token.clone()).await?;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.contains("no execution finished"), "{}", response);
+
+        token.cancel();
+        handle.abort();
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health_read_timeout() -> anyhow::Result<()> {
+        // リクエストを送ってこない接続は待ち続けない
+        let health = Health::new(HealthConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let _client = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, _) = listener.accept().await?;
+        let started = tokio::time::Instant::now();
+        assert!(health.respond(stream).await.is_err());
+        assert_eq!(started.elapsed(), READ_TIMEOUT);
+        Ok(())
+    }
+}
@@ -13,14 +13,51 @@ pub mod postgres_redis;
 #[cfg(all(feature = "sqlx", feature = "redis"))]
 pub mod sqlx_redis;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "health")]
+pub mod health;
+
+pub mod clock;
+pub mod control;
+pub mod fallible;
+pub mod group;
+pub mod lock;
+pub mod pool;
+pub mod rate_limit;
+pub mod resident;
+pub mod resource;
 pub mod retry;
+pub mod shutdown;
+pub mod signal;
+pub mod status;
+pub mod timezone;
 
-use chrono::prelude::*;
+mod runner;
+
+use chrono::{prelude::*, LocalResult, TimeDelta};
+pub use chrono_tz::Tz;
 pub use cron::Schedule;
-use std::{future::Future, time::Duration};
-use tokio::{signal::ctrl_c, spawn, task::JoinHandle, time::sleep};
+pub use fallible::{
+    make_fallible_looper_with_config, make_fallible_worker_with_config, ErrorHandler, ErrorPolicy,
+};
+pub use pool::{make_worker_pool, PoolSize};
+pub use resident::Resident;
+pub use signal::signal_handler;
+use std::{future::Future, sync::Arc, time::Duration};
+pub use timezone::LoopTimezone;
+use tokio::{signal::ctrl_c, spawn, sync::Notify, task::JoinHandle, time::sleep};
 pub use tokio_util::sync::CancellationToken;
-use tracing::debug;
+use tracing::{debug, warn};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    lock::TaskLock,
+    rate_limit::RateLimiter,
+    resource::Resource,
+    runner::{spawn_looper, spawn_worker},
+};
 
 ///
 /// LoopState
@@ -28,21 +65,270 @@ use tracing::debug;
 ///   Continue: continue loop
 ///   Terminate: terminate this loop
 ///   Duration(duration): sleep duration
+///   At(time): execute next at the time
+///   Reschedule(schedule): execute next at the next tick of the schedule,
+///     the looper keeps the schedule for the following ticks
+///   Backoff: the execution failed, execute again after the delay of BackoffPolicy,
+///     which doubles with each consecutive Backoff
 ///
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LoopState {
     AllTerminate,
     Continue,
     Terminate,
     Duration(Duration),
+    At(DateTime<Utc>),
+    Reschedule(Box<Schedule>),
+    Backoff,
+}
+
+///
+/// MisfirePolicy
+///   Skip: drop the missed ticks and wait for the next upcoming tick
+///   FireOnce: run once for the latest missed tick to catch up
+///   FireAll: run every missed tick in order
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MisfirePolicy {
+    #[default]
+    Skip,
+    FireOnce,
+    FireAll,
+}
+
+impl MisfirePolicy {
+    // 実行した時刻の次の時刻を取得する
+    pub(crate) fn next_tick(
+        &self,
+        schedule: &Schedule,
+        timezone: &LoopTimezone,
+        scheduled: &DateTime<Utc>,
+        now: &DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let tick = next_tick(schedule, timezone, scheduled)?;
+        self.missed(schedule, timezone, &tick, now)
+    }
+
+    // 起きるのが遅れてscheduledの次の時刻も過ぎていれば、実行する時刻を決め直す
+    pub(crate) fn catch_up(
+        &self,
+        schedule: &Schedule,
+        timezone: &LoopTimezone,
+        scheduled: &DateTime<Utc>,
+        now: &DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let missed = next_tick(schedule, timezone, scheduled).is_some_and(|it| it <= *now);
+        if !missed {
+            return Some(*scheduled);
+        }
+        self.missed(schedule, timezone, scheduled, now)
+    }
+
+    // scheduledから現在までの時刻を実行できなかった場合に、次に実行する時刻を取得する
+    pub(crate) fn missed(
+        &self,
+        schedule: &Schedule,
+        timezone: &LoopTimezone,
+        scheduled: &DateTime<Utc>,
+        now: &DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if scheduled > now {
+            return Some(*scheduled);
+        }
+
+        // 実行できなかった時刻がある
+        match self {
+            MisfirePolicy::Skip => {
+                let mut skipped = 1;
+                let mut next = next_tick(schedule, timezone, scheduled);
+                while let Some(it) = next.filter(|it| it <= now) {
+                    skipped += 1;
+                    next = next_tick(schedule, timezone, &it);
+                }
+                warn!(from = %scheduled, skipped, "skip missed ticks");
+                next
+            }
+            MisfirePolicy::FireOnce => {
+                let mut tick = *scheduled;
+                while let Some(next) = next_tick(schedule, timezone, &tick).filter(|it| it <= now) {
+                    tick = next;
+                }
+                Some(tick)
+            }
+            MisfirePolicy::FireAll => Some(*scheduled),
+        }
+    }
+}
+
+///
+/// OverlapPolicy
+///   Forbid: wait for the running execution, the ticks fired meanwhile are handled by MisfirePolicy
+///   Allow(max): spawn each execution as a separate task, up to max (at least 1) at the same time
+///   CancelPrevious: cancel the running execution when the next tick fires
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    #[default]
+    Forbid,
+    Allow(usize),
+    CancelPrevious,
+}
+
+///
+/// RestartPolicy
+///   Never: terminate the loop when an execution panics
+///   Always { backoff }: keep the loop running after a panic
+///   UpTo { max_restarts, backoff }: keep the loop running for up to max_restarts consecutive panics
+///
+/// After a panic the loop continues as if the task returned LoopState::Continue,
+/// but not earlier than the backoff, which doubles with each consecutive panic.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    Always {
+        backoff: Duration,
+    },
+    UpTo {
+        max_restarts: u64,
+        backoff: Duration,
+    },
+}
+
+impl RestartPolicy {
+    // 連続したパニックの回数から再開までの時間を取得する、再開しない場合はNone
+    pub(crate) fn backoff(&self, panics: u64) -> Option<Duration> {
+        let backoff = match self {
+            RestartPolicy::Never => return None,
+            RestartPolicy::Always { backoff } => backoff,
+            RestartPolicy::UpTo {
+                max_restarts,
+                backoff,
+            } => {
+                if panics > *max_restarts {
+                    return None;
+                }
+                backoff
+            }
+        };
+        Some(exponential(*backoff, panics))
+    }
+}
+
+///
+/// BackoffPolicy
+///   initial: delay after the first LoopState::Backoff
+///   max: upper bound of the delay, which doubles with each consecutive LoopState::Backoff
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(300),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    // 連続した失敗の回数から次の実行までの時間を取得する
+    pub(crate) fn delay(&self, failures: u64) -> Duration {
+        exponential(self.initial, failures).min(self.max)
+    }
+}
+
+// n回目で base * 2^(n-1)
+fn exponential(base: Duration, n: u64) -> Duration {
+    let exponent = n.saturating_sub(1).min(u32::BITS as u64 - 1) as u32;
+    base.saturating_mul(2_u32.pow(exponent))
+}
+
+///
+/// LoopConfig
+///   timezone: timezone in which the cron schedule is evaluated, a chrono-tz zone, FixedOffset or Local
+///   misfire: how ticks missed by an overrun or a suspended process are handled (looper only)
+///   overlap: whether executions may run concurrently (looper only)
+///   restart: whether the loop keeps running after an execution panics
+///   backoff: delays after LoopState::Backoff
+///   timeout: upper bound of each execution, the execution is dropped and
+///     the token passed to the task is cancelled when exceeded
+///   on_timeout: LoopState applied instead when an execution timed out
+///   rate_limit: executions over the limit are delayed until the budget is refilled,
+///     the ticks passed meanwhile are handled by MisfirePolicy and scheduled keeps the tick
+///   lock: taken before each execution keyed by the name, the tick is skipped when not taken (looper only)
+///     name is required to share the lock between processes
+///   wake: ends the idle sleep and executes at once when notified (worker only)
+///     notify_one while executing makes the next execution start without sleeping
+///   clock: source of the current time, MockClock for tests
+///   name: name of the task in the tracing spans, the status and the metrics
+///     "looper-{id}" or "worker-{id}" when empty
+///
+#[derive(Debug, Clone)]
+pub struct LoopConfig {
+    pub timezone: LoopTimezone,
+    pub misfire: MisfirePolicy,
+    pub overlap: OverlapPolicy,
+    pub restart: RestartPolicy,
+    pub backoff: BackoffPolicy,
+    pub timeout: Option<Duration>,
+    pub on_timeout: LoopState,
+    pub rate_limit: Option<RateLimiter>,
+    pub lock: Option<Arc<dyn TaskLock>>,
+    pub wake: Option<Arc<Notify>>,
+    pub clock: Arc<dyn Clock>,
+    pub name: String,
+}
+
+impl Default for LoopConfig {
+    fn default() -> Self {
+        Self {
+            timezone: LoopTimezone::default(),
+            misfire: MisfirePolicy::default(),
+            overlap: OverlapPolicy::default(),
+            restart: RestartPolicy::default(),
+            backoff: BackoffPolicy::default(),
+            timeout: None,
+            on_timeout: LoopState::Continue,
+            rate_limit: None,
+            lock: None,
+            wake: None,
+            clock: Arc::new(SystemClock),
+            name: String::new(),
+        }
+    }
+}
+
+///
+/// TaskContext
+///   scheduled: time the execution was scheduled for
+///   started: time the execution actually started
+///   budget: executions left without waiting by LoopConfig rate_limit, None without it
+///
+#[derive(Debug, Clone)]
+pub struct TaskContext {
+    pub scheduled: DateTime<Utc>,
+    pub started: DateTime<Utc>,
+    pub budget: Option<u32>,
 }
 
 impl LoopState {
     pub(crate) fn looper(
         &self,
         token: &CancellationToken,
-        now: &DateTime<Utc>,
-        schedule: &Schedule,
+        context: &TaskContext,
+        schedule: &mut Schedule,
+        failures: &mut u64,
+        config: &LoopConfig,
     ) -> Option<DateTime<Utc>> {
+        if *self != LoopState::Backoff {
+            *failures = 0;
+        }
         match self {
             LoopState::AllTerminate => {
                 token.cancel();
@@ -51,11 +337,26 @@ impl LoopState {
             LoopState::Terminate => None,
             LoopState::Duration(duration) => {
                 // 指定時間待つ
-                Some(*now + *duration)
+                Some(context.started + *duration)
+            }
+            LoopState::At(at) => Some(*at),
+            LoopState::Reschedule(new_schedule) => {
+                // 以降は新しいスケジュールで動く
+                *schedule = (**new_schedule).clone();
+                next_tick(schedule, &config.timezone, &config.clock.now())
+            }
+            LoopState::Backoff => {
+                *failures += 1;
+                Some(context.started + config.backoff.delay(*failures))
             }
             LoopState::Continue => {
                 // 次の時間取得
-                schedule.upcoming(Utc).next()
+                config.misfire.next_tick(
+                    schedule,
+                    &config.timezone,
+                    &context.scheduled,
+                    &config.clock.now(),
+                )
             }
         }
     }
@@ -63,7 +364,12 @@ impl LoopState {
         &self,
         token: &CancellationToken,
         now: &DateTime<Utc>,
+        failures: &mut u64,
+        config: &LoopConfig,
     ) -> Option<DateTime<Utc>> {
+        if *self != LoopState::Backoff {
+            *failures = 0;
+        }
         match self {
             LoopState::AllTerminate => {
                 token.cancel();
@@ -74,6 +380,12 @@ impl LoopState {
                 // 指定時間待つ
                 Some(*now + *duration)
             }
+            LoopState::At(at) => Some(*at),
+            LoopState::Reschedule(schedule) => next_tick(schedule, &config.timezone, now),
+            LoopState::Backoff => {
+                *failures += 1;
+                Some(*now + config.backoff.delay(*failures))
+            }
             LoopState::Continue => {
                 // 即時処理を行う
                 Some(*now)
@@ -82,26 +394,68 @@ impl LoopState {
     }
 }
 
-// 次の処理までスリープする
-#[allow(dead_code)]
+///
+/// Returns the first tick of the schedule strictly after `after`, evaluating
+/// the schedule in the wall-clock time of `timezone`.
+///   DST gap: a local time that does not exist is shifted forward by the length of the gap
+///     (e.g. 02:30 becomes 03:30 when clocks jump from 02:00 to 03:00)
+///   Repeated hour: each local time fires once, at its first occurrence
+///
+pub fn next_tick<T: TimeZone>(
+    schedule: &Schedule,
+    timezone: &T,
+    after: &DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    // cronはローカル時刻をUTCとして扱って計算する
+    let mut local = after.with_timezone(timezone).naive_local();
+    loop {
+        let candidate = schedule
+            .after(&Utc.from_utc_datetime(&local))
+            .next()?
+            .naive_utc();
+        let tick = match timezone.from_local_datetime(&candidate) {
+            LocalResult::Single(tick) => tick.with_timezone(&Utc),
+            // 2回目の同じ時刻は実行しない
+            LocalResult::Ambiguous(earliest, _) => earliest.with_timezone(&Utc),
+            // 存在しない時刻はギャップ前のオフセットで解釈する
+            LocalResult::None => {
+                let offset = timezone
+                    .offset_from_utc_datetime(&(candidate - TimeDelta::days(1)))
+                    .fix();
+                Utc.from_utc_datetime(&(candidate - offset))
+            }
+        };
+        if tick > *after {
+            return Some(tick);
+        }
+        local = candidate;
+    }
+}
+
+// 次の処理までスリープする、トークンがキャンセルされたらすぐに戻る
+// stop_check_durationが指定されていれば、その間隔でも起きる
 pub(crate) async fn execute_sleep(
-    stop_check_duration: &Duration,
+    token: &CancellationToken,
+    stop_check_duration: &Option<Duration>,
     next_tick: &DateTime<Utc>,
-    now: &DateTime<Utc>,
+    clock: &dyn Clock,
 ) {
     // next_tickが過去ならsleepせずに終了
-    if now >= next_tick {
+    let now = clock.now();
+    if now >= *next_tick {
         return;
     }
 
-    // 上記でチェックしているので、as u64で問題無い。
-    let tick_duration = Duration::from_secs((*next_tick - *now).num_seconds() as u64);
-    let duration = if stop_check_duration < &tick_duration {
-        stop_check_duration
-    } else {
-        &tick_duration
+    // 上記でチェックしているので、to_stdは失敗しない。1秒未満も切り捨てない
+    let tick_duration = (*next_tick - now).to_std().unwrap_or_default();
+    let duration = match stop_check_duration {
+        Some(stop_check_duration) if stop_check_duration < &tick_duration => stop_check_duration,
+        _ => &tick_duration,
     };
-    sleep(*duration).await;
+    tokio::select! {
+        _ = sleep(*duration) => {}
+        _ = token.cancelled() => {}
+    }
 }
 
 pub fn ctrl_c_handler() -> (JoinHandle<()>, CancellationToken) {
@@ -123,48 +477,53 @@ pub fn ctrl_c_handler() -> (JoinHandle<()>, CancellationToken) {
 pub fn make_looper<Fut1, Fut2>(
     token: CancellationToken,
     schedule: Schedule,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(DateTime<Utc>) -> Fut1 + Send + Sync + 'static,
     stop_function: impl Fn() -> Fut2 + Send + Sync + 'static,
 ) -> JoinHandle<()>
 where
-    Fut1: Future<Output = LoopState> + Send,
+    Fut1: Future<Output = LoopState> + Send + 'static,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        let mut next_tick: DateTime<Utc> = match schedule.upcoming(Utc).next() {
-            Some(next_tick) => next_tick,
-            None => {
-                stop_function().await;
-                return;
-            }
-        };
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function().await;
-                break;
-            }
-
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now).await.looper(&token, &now, &schedule) {
-                    next_tick = res;
-                } else {
-                    stop_function().await;
-                    break;
-                }
-            }
+    make_looper_with_config(
+        (),
+        token,
+        schedule,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, _, _| task_function(context.started),
+        move |_| stop_function(),
+    )
+}
 
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+pub fn make_looper_with_config<R, Fut1, Fut2>(
+    resource: R,
+    token: CancellationToken,
+    schedule: Schedule,
+    stop_check_duration: impl Into<Option<Duration>>,
+    config: LoopConfig,
+    task_function: impl Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    stop_function: impl Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    Fut1: Future<Output = LoopState> + Send + 'static,
+    Fut2: Future<Output = ()> + Send,
+{
+    spawn_looper(
+        token,
+        schedule,
+        stop_check_duration.into(),
+        config,
+        resource,
+        task_function,
+        stop_function,
+    )
 }
 
 pub fn make_worker<Fut1, Fut2>(
     token: CancellationToken,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(DateTime<Utc>) -> Fut1 + Send + Sync + 'static,
     stop_function: impl Fn() -> Fut2 + Send + Sync + 'static,
 ) -> JoinHandle<()>
@@ -172,29 +531,647 @@ where
     Fut1: Future<Output = LoopState> + Send,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        // 動き出した瞬間は実行する
-        let mut next_tick: DateTime<Utc> = Utc::now();
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function().await;
-                break;
-            }
+    make_worker_with_config(
+        (),
+        token,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, _, _| task_function(context.started),
+        move |_| stop_function(),
+    )
+}
 
-            // 現在時間と次実行する処理の時間をチェックする
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now).await.worker(&token, &now) {
-                    next_tick = res;
-                } else {
-                    stop_function().await;
-                    break;
+pub fn make_worker_with_config<R, Fut1, Fut2>(
+    resource: R,
+    token: CancellationToken,
+    stop_check_duration: impl Into<Option<Duration>>,
+    config: LoopConfig,
+    task_function: impl Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    stop_function: impl Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    Fut1: Future<Output = LoopState> + Send,
+    Fut2: Future<Output = ()> + Send,
+{
+    spawn_worker(
+        token,
+        stop_check_duration.into(),
+        config,
+        resource,
+        task_function,
+        stop_function,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_next_tick() -> anyhow::Result<()> {
+        // 09:00 JST
+        let schedule = Schedule::from_str("0 0 9 * * *")?;
+        let tz = Tz::from_str("Asia/Tokyo").unwrap();
+        assert_eq!(
+            next_tick(&schedule, &tz, &utc("2024-11-13T00:00:00Z")),
+            Some(utc("2024-11-14T00:00:00Z"))
+        );
+
+        // DST gap: 02:30 EST does not exist, shifted to 03:30 EDT
+        let schedule = Schedule::from_str("0 30 2 * * *")?;
+        let tz = Tz::from_str("America/New_York").unwrap();
+        assert_eq!(
+            next_tick(&schedule, &tz, &utc("2024-03-09T08:00:00Z")),
+            Some(utc("2024-03-10T07:30:00Z"))
+        );
+        assert_eq!(
+            next_tick(&schedule, &tz, &utc("2024-03-10T07:30:00Z")),
+            Some(utc("2024-03-11T06:30:00Z"))
+        );
+
+        // Repeated hour: 01:30 fires only once
+        let schedule = Schedule::from_str("0 30 1 * * *")?;
+        assert_eq!(
+            next_tick(&schedule, &tz, &utc("2024-11-02T06:00:00Z")),
+            Some(utc("2024-11-03T05:30:00Z"))
+        );
+        assert_eq!(
+            next_tick(&schedule, &tz, &utc("2024-11-03T05:30:00Z")),
+            Some(utc("2024-11-04T06:30:00Z"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_misfire() -> anyhow::Result<()> {
+        let schedule = Schedule::from_str("*/10 * * * * *")?;
+        let scheduled = utc("2024-11-13T00:00:00Z");
+        let now = utc("2024-11-13T00:00:35Z");
+        let next =
+            |policy: MisfirePolicy| policy.next_tick(&schedule, &Tz::UTC.into(), &scheduled, &now);
+        assert_eq!(next(MisfirePolicy::Skip), Some(utc("2024-11-13T00:00:40Z")));
+        assert_eq!(
+            next(MisfirePolicy::FireOnce),
+            Some(utc("2024-11-13T00:00:30Z"))
+        );
+        assert_eq!(
+            next(MisfirePolicy::FireAll),
+            Some(utc("2024-11-13T00:00:10Z"))
+        );
+
+        // 間に合っていれば次の時刻
+        let now = utc("2024-11-13T00:00:05Z");
+        let next =
+            |policy: MisfirePolicy| policy.next_tick(&schedule, &Tz::UTC.into(), &scheduled, &now);
+        assert_eq!(
+            next(MisfirePolicy::FireAll),
+            Some(utc("2024-11-13T00:00:10Z"))
+        );
+
+        Ok(())
+    }
+
+    // */10のlooperをMockClockで動かし、(scheduled, started)の秒を返す
+    // taskは実行時間を返し、その時間だけかかる
+    async fn run_misfire(
+        misfire: MisfirePolicy,
+        duration: Duration,
+        task: impl Fn(usize) -> Duration + Send + Sync + 'static,
+        clock: Arc<crate::clock::MockClock>,
+        before: impl Future<Output = ()>,
+    ) -> anyhow::Result<Vec<(i64, i64)>> {
+        use std::sync::Mutex;
+
+        let token = CancellationToken::new();
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let task_ticks = ticks.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("*/10 * * * * *")?,
+            None,
+            LoopConfig {
+                misfire,
+                clock: clock.clone(),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                let mut ticks = task_ticks.lock().unwrap();
+                let elapsed = task(ticks.len());
+                ticks.push((context.scheduled.timestamp(), context.started.timestamp()));
+                async move {
+                    tokio::time::sleep(elapsed).await;
+                    LoopState::Continue
                 }
+            },
+            |_| async {},
+        );
+        before.await;
+        tokio::time::sleep(duration).await;
+        token.cancel();
+        handle.await?;
+        let ticks = ticks.lock().unwrap().clone();
+        Ok(ticks)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_misfire_overrun() -> anyhow::Result<()> {
+        use crate::clock::MockClock;
+
+        // 最初の実行に35秒かかる
+        let overrun = |n| {
+            if n == 0 {
+                Duration::from_secs(35)
+            } else {
+                Duration::ZERO
             }
+        };
+        let run = |misfire| {
+            let clock = Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap()));
+            run_misfire(misfire, Duration::from_secs(55), overrun, clock, async {})
+        };
+        assert_eq!(run(MisfirePolicy::Skip).await?, vec![(10, 10), (50, 50)]);
+        assert_eq!(
+            run(MisfirePolicy::FireOnce).await?,
+            vec![(10, 10), (40, 45), (50, 50)]
+        );
+        assert_eq!(
+            run(MisfirePolicy::FireAll).await?,
+            vec![(10, 10), (20, 45), (30, 45), (40, 45), (50, 50)]
+        );
+        Ok(())
+    }
 
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+    #[tokio::test(start_paused = true)]
+    async fn test_misfire_late_wake() -> anyhow::Result<()> {
+        use crate::clock::MockClock;
+
+        // 6秒の時点で45秒まで止まっていたことにする
+        let run = |misfire| {
+            let clock = Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap()));
+            let suspended = clock.clone();
+            let before = async move {
+                tokio::time::sleep(Duration::from_secs(6)).await;
+                suspended.set(Utc.timestamp_opt(45, 0).unwrap());
+            };
+            run_misfire(
+                misfire,
+                Duration::from_secs(14),
+                |_| Duration::ZERO,
+                clock,
+                before,
+            )
+        };
+        assert_eq!(run(MisfirePolicy::Skip).await?, vec![(50, 50)]);
+        assert_eq!(
+            run(MisfirePolicy::FireOnce).await?,
+            vec![(40, 49), (50, 50)]
+        );
+        assert_eq!(
+            run(MisfirePolicy::FireAll).await?,
+            vec![(10, 49), (20, 49), (30, 49), (40, 49), (50, 50)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_backoff() {
+        let backoff = Duration::from_millis(100);
+        let policy = RestartPolicy::UpTo {
+            max_restarts: 3,
+            backoff,
+        };
+        assert_eq!(policy.backoff(1), Some(backoff));
+        assert_eq!(policy.backoff(3), Some(backoff * 4));
+        assert_eq!(policy.backoff(4), None);
+        assert_eq!(RestartPolicy::Never.backoff(1), None);
+        assert_eq!(
+            RestartPolicy::Always { backoff }.backoff(100),
+            Some(backoff.saturating_mul(u32::MAX / 2 + 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_worker_panic() -> anyhow::Result<()> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (task_count, stop_count) = (count.clone(), stopped.clone());
+        let handle = make_worker_with_config(
+            (),
+            CancellationToken::new(),
+            Duration::from_millis(10),
+            LoopConfig {
+                restart: RestartPolicy::UpTo {
+                    max_restarts: 3,
+                    backoff: Duration::from_millis(1),
+                },
+                ..Default::default()
+            },
+            move |_, _, _| {
+                let count = task_count.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if count < 2 {
+                        panic!("panic {}", count);
+                    }
+                    LoopState::Terminate
+                }
+            },
+            move |_| {
+                stop_count.fetch_add(1, Ordering::SeqCst);
+                async {}
+            },
+        );
+        handle.await?;
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!(stopped.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_immediately() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let handle = make_worker(
+            token.clone(),
+            None,
+            |_| async { LoopState::Duration(Duration::from_secs(60)) },
+            || async {},
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        token.cancel();
+        tokio::time::timeout(Duration::from_millis(100), handle).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sub_second_sleep() -> anyhow::Result<()> {
+        let now = Utc::now();
+        let started = std::time::Instant::now();
+        execute_sleep(
+            &CancellationToken::new(),
+            &None,
+            &(now + Duration::from_millis(300)),
+            &SystemClock,
+        )
+        .await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(290), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sub_second_duration() -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        use crate::clock::MockClock;
+
+        // 1秒未満の間隔でも秒の途中から正確に待つ
+        let token = CancellationToken::new();
+        let starts = Arc::new(Mutex::new(vec![]));
+        let task_starts = starts.clone();
+        let handle = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 300_000_000).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_starts
+                    .lock()
+                    .unwrap()
+                    .push(context.started.timestamp_millis());
+                async { LoopState::Duration(Duration::from_millis(200)) }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        token.cancel();
+        handle.await?;
+
+        assert_eq!(*starts.lock().unwrap(), vec![300, 500, 700, 900]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_second_boundary() -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        use crate::clock::MockClock;
+
+        // 秒の途中から始めても、秒の境界で実行する
+        let token = CancellationToken::new();
+        let contexts = Arc::new(Mutex::new(vec![]));
+        let task_contexts = contexts.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("* * * * * *")?,
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 400_000_000).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_contexts.lock().unwrap().push((
+                    context.scheduled.timestamp_millis(),
+                    context.started.timestamp_millis(),
+                ));
+                async { LoopState::Continue }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        token.cancel();
+        handle.await?;
+
+        assert_eq!(*contexts.lock().unwrap(), vec![(1000, 1000), (2000, 2000)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wake() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let token = CancellationToken::new();
+        let wake = Arc::new(Notify::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let task_count = count.clone();
+        let handle = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                wake: Some(wake.clone()),
+                ..Default::default()
+            },
+            move |_, _, _| {
+                task_count.fetch_add(1, Ordering::SeqCst);
+                async { LoopState::Duration(Duration::from_secs(60)) }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        wake.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        token.cancel();
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock() -> anyhow::Result<()> {
+        use crate::clock::MockClock;
+        use std::sync::Mutex;
+
+        // DST切り替え日の直前から36時間を待たずに進める
+        let token = CancellationToken::new();
+        let contexts = Arc::new(Mutex::new(vec![]));
+        let task_contexts = contexts.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("0 30 2 * * *")?,
+            None,
+            LoopConfig {
+                timezone: Tz::from_str("America/New_York").unwrap().into(),
+                clock: Arc::new(MockClock::new(utc("2024-03-10T06:59:59Z"))),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_contexts.lock().unwrap().push(context);
+                async { LoopState::Continue }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_secs(36 * 60 * 60)).await;
+        token.cancel();
+        handle.await?;
+
+        let contexts = contexts.lock().unwrap();
+        let ticks: Vec<_> = contexts
+            .iter()
+            .map(|it| (it.scheduled, it.started))
+            .collect();
+        assert_eq!(
+            ticks,
+            vec![
+                (utc("2024-03-10T07:30:00Z"), utc("2024-03-10T07:30:00Z")),
+                (utc("2024-03-11T06:30:00Z"), utc("2024-03-11T06:30:00Z")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_task_name() -> anyhow::Result<()> {
+        use crate::status::{find_status, lock};
+
+        let token = CancellationToken::new();
+        let named = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                name: "job".to_owned(),
+                ..Default::default()
+            },
+            |_, _, _| async { LoopState::Duration(Duration::from_secs(60)) },
+            |_| async {},
+        );
+        let unnamed = make_worker(
+            token.clone(),
+            None,
+            |_| async { LoopState::Duration(Duration::from_secs(60)) },
+            || async {},
+        );
+        let named_status = find_status(named.id()).map(|it| lock(&it).clone()).unwrap();
+        let unnamed_status = find_status(unnamed.id())
+            .map(|it| lock(&it).clone())
+            .unwrap();
+        assert_eq!(named_status.name, "job");
+        assert_eq!(unnamed_status.name, format!("worker-{}", unnamed_status.id));
+        token.cancel();
+        named.await?;
+        unnamed.await?;
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loop_state() -> anyhow::Result<()> {
+        use crate::clock::MockClock;
+        use std::sync::Mutex;
+
+        // 失敗が続くと間隔が倍になり、成功すると戻る
+        let token = CancellationToken::new();
+        let started = Arc::new(Mutex::new(vec![]));
+        let task_started = started.clone();
+        let states = Arc::new(Mutex::new(vec![
+            LoopState::Backoff,
+            LoopState::Backoff,
+            LoopState::Backoff,
+            LoopState::At(utc("2024-01-01T01:00:00Z")),
+            LoopState::Backoff,
+            LoopState::Reschedule(Box::new(Schedule::from_str("0 0 3 * * *")?)),
+            LoopState::Terminate,
+        ]));
+        let handle = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(utc("2024-01-01T00:00:00Z"))),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_started.lock().unwrap().push(context.started);
+                let state = states.lock().unwrap().remove(0);
+                async move { state }
+            },
+            |_| async {},
+        );
+        handle.await?;
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec![
+                utc("2024-01-01T00:00:00Z"),
+                utc("2024-01-01T00:00:01Z"),
+                utc("2024-01-01T00:00:03Z"),
+                utc("2024-01-01T00:00:07Z"),
+                utc("2024-01-01T01:00:00Z"),
+                utc("2024-01-01T01:00:01Z"),
+                utc("2024-01-01T03:00:00Z"),
+            ]
+        );
+
+        // スケジュールを変えると以降は新しいスケジュールで動く
+        let started = Arc::new(Mutex::new(vec![]));
+        let task_started = started.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("0 0 * * * *")?,
+            None,
+            LoopConfig {
+                clock: Arc::new(MockClock::new(utc("2024-01-01T00:00:00Z"))),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                let mut started = task_started.lock().unwrap();
+                started.push(context.started);
+                let state = match started.len() {
+                    1 => LoopState::Reschedule(Box::new(
+                        Schedule::from_str("0 0 0/6 * * *").unwrap(),
+                    )),
+                    3 => LoopState::Terminate,
+                    _ => LoopState::Continue,
+                };
+                async move { state }
+            },
+            |_| async {},
+        );
+        handle.await?;
+        assert_eq!(
+            *started.lock().unwrap(),
+            vec![
+                utc("2024-01-01T01:00:00Z"),
+                utc("2024-01-01T06:00:00Z"),
+                utc("2024-01-01T12:00:00Z"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() -> anyhow::Result<()> {
+        use crate::status::{find_status, lock, ExitReason};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // 止まった処理は打ち切られ、渡されたトークンもキャンセルされる
+        let token = CancellationToken::new();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let task_cancelled = cancelled.clone();
+        let handle = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                timeout: Some(Duration::from_secs(10)),
+                on_timeout: LoopState::Terminate,
+                ..Default::default()
+            },
+            move |_, _, token| {
+                let cancelled = task_cancelled.clone();
+                async move {
+                    let child = token.clone();
+                    tokio::spawn(async move {
+                        child.cancelled().await;
+                        cancelled.store(true, Ordering::SeqCst);
+                    });
+                    std::future::pending::<()>().await;
+                    LoopState::Continue
+                }
+            },
+            |_| async {},
+        );
+        let status = find_status(handle.id()).unwrap();
+        let started = tokio::time::Instant::now();
+        handle.await?;
+        tokio::task::yield_now().await;
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert!(!token.is_cancelled());
+        assert_eq!(lock(&status).exit, Some(ExitReason::Terminated));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_overlap_terminate() -> anyhow::Result<()> {
+        use crate::clock::MockClock;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Allow(0)でも1つは実行し、並行実行のTerminateは次の時刻を待たずに止める
+        let token = CancellationToken::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("0 0 * * * *")?,
+            None,
+            LoopConfig {
+                overlap: OverlapPolicy::Allow(0),
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(3599, 0).unwrap())),
+                ..Default::default()
+            },
+            move |_, _, _| {
+                task_runs.fetch_add(1, Ordering::SeqCst);
+                async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    LoopState::Terminate
+                }
+            },
+            |_| async {},
+        );
+        tokio::time::timeout(Duration::from_secs(60), handle).await??;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(!token.is_cancelled());
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+use std::fmt::Debug;
+
+use futures_util::future::BoxFuture;
+
+///
+/// TaskLock
+///   lock taken before each execution of a looper given by LoopConfig lock,
+///   the tick is skipped without an execution while the lock is not taken,
+///   e.g. postgres::AdvisoryLock executes the looper on one replica at each tick
+///
+pub trait TaskLock: Debug + Send + Sync + 'static {
+    ///
+    /// Takes the lock keyed by the name of the task, None when held by another or on errors
+    ///
+    fn try_lock<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<Box<dyn TaskLockGuard>>>;
+}
+
+///
+/// TaskLockGuard
+///   lock held while the execution runs and unlocked after it,
+///   dropped without unlock when the looper is aborted
+///
+pub trait TaskLockGuard: Send {
+    fn unlock(self: Box<Self>) -> BoxFuture<'static, ()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use chrono::prelude::*;
+    use cron::Schedule;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        make_looper_with_config,
+        status::{find_status, lock},
+        LoopConfig, LoopState, OverlapPolicy,
+    };
+
+    // 2回に1回だけ取れるロック
+    #[derive(Debug, Default)]
+    struct Alternate {
+        tries: AtomicUsize,
+        unlocked: Arc<AtomicUsize>,
+    }
+
+    struct Guard(Arc<AtomicUsize>);
+
+    impl TaskLock for Alternate {
+        fn try_lock<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Option<Box<dyn TaskLockGuard>>> {
+            let locked = self.tries.fetch_add(1, Ordering::SeqCst).is_multiple_of(2);
+            let guard = Guard(self.unlocked.clone());
+            Box::pin(async move { locked.then(|| Box::new(guard) as Box<dyn TaskLockGuard>) })
+        }
+    }
+
+    impl TaskLockGuard for Guard {
+        fn unlock(self: Box<Self>) -> BoxFuture<'static, ()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async {})
+        }
+    }
+
+    // 実行中は他から取れないロック、中断で捨てられた場合も手放す
+    #[derive(Debug, Default)]
+    struct Exclusive {
+        tries: AtomicUsize,
+        held: Arc<AtomicBool>,
+    }
+
+    struct ExclusiveGuard(Arc<AtomicBool>);
+
+    impl TaskLock for Exclusive {
+        fn try_lock<'a>(&'a self, _: &'a str) -> BoxFuture<'a, Option<Box<dyn TaskLockGuard>>> {
+            self.tries.fetch_add(1, Ordering::SeqCst);
+            let locked = !self.held.swap(true, Ordering::SeqCst);
+            let guard = ExclusiveGuard(self.held.clone());
+            Box::pin(async move { locked.then(|| Box::new(guard) as Box<dyn TaskLockGuard>) })
+        }
+    }
+
+    impl TaskLockGuard for ExclusiveGuard {
+        fn unlock(self: Box<Self>) -> BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+    }
+
+    impl Drop for ExclusiveGuard {
+        fn drop(&mut self) {
+            self.0.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // 10秒ごとにduration秒かかる処理を動かし、開始した秒とロックを取ろうとした回数を返す
+    async fn run_exclusive(
+        overlap: OverlapPolicy,
+        duration: u64,
+    ) -> anyhow::Result<(Vec<i64>, usize)> {
+        let token = CancellationToken::new();
+        let task_lock = Arc::new(Exclusive::default());
+        let started = Arc::new(Mutex::new(vec![]));
+        let task_started = started.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("*/10 * * * * *")?,
+            None,
+            LoopConfig {
+                overlap,
+                lock: Some(task_lock.clone()),
+                name: "test_task_lock_overlap".to_owned(),
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_started
+                    .lock()
+                    .unwrap()
+                    .push(context.started.timestamp());
+                async move {
+                    tokio::time::sleep(Duration::from_secs(duration)).await;
+                    LoopState::Continue
+                }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_secs(55)).await;
+        token.cancel();
+        handle.await?;
+        let started = started.lock().unwrap().clone();
+        Ok((started, task_lock.tries.load(Ordering::SeqCst)))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task_lock_overlap() -> anyhow::Result<()> {
+        // 上限まで動いている時刻はロックを取ろうとせずに飛ばす
+        let (started, tries) = run_exclusive(OverlapPolicy::Allow(1), 15).await?;
+        assert_eq!(started, vec![10, 30, 50]);
+        assert_eq!(tries, 3);
+
+        // 前の実行を中断してロックを手放させてから取る
+        let (started, tries) = run_exclusive(OverlapPolicy::CancelPrevious, 60).await?;
+        assert_eq!(started, vec![10, 20, 30, 40, 50]);
+        assert_eq!(tries, 5);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_task_lock() -> anyhow::Result<()> {
+        // ロックが取れなかった時刻は実行も記録もしない
+        let token = CancellationToken::new();
+        let task_lock = Arc::new(Alternate::default());
+        let unlocked = task_lock.unlocked.clone();
+        let started = Arc::new(Mutex::new(vec![]));
+        let task_started = started.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("*/10 * * * * *")?,
+            None,
+            LoopConfig {
+                lock: Some(task_lock),
+                name: "test_task_lock".to_owned(),
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_started
+                    .lock()
+                    .unwrap()
+                    .push(context.started.timestamp());
+                async { LoopState::Continue }
+            },
+            |_| async {},
+        );
+        let status = find_status(handle.id()).unwrap();
+        tokio::time::sleep(Duration::from_secs(55)).await;
+        assert_eq!(*started.lock().unwrap(), vec![10, 30, 50]);
+        assert_eq!(lock(&status).runs, 3);
+        assert_eq!(unlocked.load(Ordering::SeqCst), 3);
+
+        token.cancel();
+        handle.await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt::Write;
+
+use chrono::prelude::*;
+
+use crate::{
+    status::{task_statuses, TaskKind, TaskStatus},
+    LoopState,
+};
+
+// 実行時間のヒストグラムの境界(秒)
+const BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+///
+/// TaskMetrics
+///   outcomes: number of executions by returned LoopState
+///     (Continue, Duration, Terminate, AllTerminate, At, Reschedule, Backoff)
+///   pool_errors: number of failed resource acquisitions
+///   duration_buckets: number of executions within each bucket of duration
+///   duration_sum: total seconds of executions
+///   duration_count: number of finished executions
+///   last_success: time the last execution finished without panic
+///
+#[derive(Debug, Clone, Default)]
+pub struct TaskMetrics {
+    pub outcomes: [u64; 7],
+    pub pool_errors: u64,
+    pub duration_buckets: [u64; BUCKETS.len()],
+    pub duration_sum: f64,
+    pub duration_count: u64,
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+impl TaskMetrics {
+    pub(crate) fn record(
+        &mut self,
+        state: Option<&LoopState>,
+        started: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) {
+        let seconds = (now - started).to_std().unwrap_or_default().as_secs_f64();
+        for (bucket, le) in self.duration_buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        self.duration_sum += seconds;
+        self.duration_count += 1;
+        let Some(state) = state else {
+            return;
+        };
+        let index = match state {
+            LoopState::Continue => 0,
+            LoopState::Duration(_) => 1,
+            LoopState::Terminate => 2,
+            LoopState::AllTerminate => 3,
+            LoopState::At(_) => 4,
+            LoopState::Reschedule(_) => 5,
+            LoopState::Backoff => 6,
+        };
+        self.outcomes[index] += 1;
+        self.last_success = Some(now);
+    }
+}
+
+///
+/// Renders the metrics of every looper and worker alive in this process
+/// in the Prometheus text exposition format
+///
+pub fn render_metrics() -> String {
+    render(&task_statuses())
+}
+
+fn render(statuses: &[TaskStatus]) -> String {
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, f: &dyn Fn(&mut String, &TaskStatus)| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for status in statuses {
+            f(&mut out, status);
+        }
+    };
+
+    family(
+        "resident_task_runs_total",
+        "counter",
+        "Number of started executions.",
+        &|out, status| {
+            sample(
+                out,
+                "resident_task_runs_total",
+                status,
+                "",
+                status.runs as f64,
+            )
+        },
+    );
+    family(
+        "resident_task_outcomes_total",
+        "counter",
+        "Number of executions by returned LoopState.",
+        &|out, status| {
+            for (state, count) in [
+                "continue",
+                "duration",
+                "terminate",
+                "all_terminate",
+                "at",
+                "reschedule",
+                "backoff",
+            ]
+            .iter()
+            .zip(status.metrics.outcomes)
+            {
+                let labels = format!(",state=\"{}\"", state);
+                sample(
+                    out,
+                    "resident_task_outcomes_total",
+                    status,
+                    &labels,
+                    count as f64,
+                );
+            }
+        },
+    );
+    family(
+        "resident_task_panics_total",
+        "counter",
+        "Number of panicked executions.",
+        &|out, status| {
+            sample(
+                out,
+                "resident_task_panics_total",
+                status,
+                "",
+                status.panics as f64,
+            )
+        },
+    );
+    family(
+        "resident_task_pool_errors_total",
+        "counter",
+        "Number of failed resource acquisitions.",
+        &|out, status| {
+            let value = status.metrics.pool_errors as f64;
+            sample(out, "resident_task_pool_errors_total", status, "", value)
+        },
+    );
+    family(
+        "resident_task_duration_seconds",
+        "histogram",
+        "Duration of executions.",
+        &|out, status| {
+            let name = "resident_task_duration_seconds_bucket";
+            for (le, count) in BUCKETS.iter().zip(status.metrics.duration_buckets) {
+                let labels = format!(",le=\"{}\"", le);
+                sample(out, name, status, &labels, count as f64);
+            }
+            let count = status.metrics.duration_count as f64;
+            sample(out, name, status, ",le=\"+Inf\"", count);
+            let sum = status.metrics.duration_sum;
+            sample(out, "resident_task_duration_seconds_sum", status, "", sum);
+            sample(
+                out,
+                "resident_task_duration_seconds_count",
+                status,
+                "",
+                count,
+            );
+        },
+    );
+    family(
+        "resident_task_last_success_timestamp_seconds",
+        "gauge",
+        "Time the last execution finished without panic.",
+        &|out, status| {
+            if let Some(last_success) = status.metrics.last_success {
+                let value = last_success.timestamp_millis() as f64 / 1000.0;
+                sample(
+                    out,
+                    "resident_task_last_success_timestamp_seconds",
+                    status,
+                    "",
+                    value,
+                );
+            }
+        },
+    );
+    family(
+        "resident_task_running",
+        "gauge",
+        "Number of executions in progress.",
+        &|out, status| {
+            sample(
+                out,
+                "resident_task_running",
+                status,
+                "",
+                status.running as f64,
+            )
+        },
+    );
+    out
+}
+
+fn sample(out: &mut String, name: &str, status: &TaskStatus, labels: &str, value: f64) {
+    let kind = match status.kind {
+        TaskKind::Looper => "looper",
+        TaskKind::Worker => "worker",
+    };
+    let _ = writeln!(
+        out,
+        "{}{{task=\"{}\",kind=\"{}\"{}}} {}",
+        name,
+        escape(&status.name),
+        kind,
+        labels,
+        value
+    );
+}
+
+// ラベルの値のエスケープ
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        make_worker,
+        status::{find_status, lock},
+        CancellationToken,
+    };
+
+    #[tokio::test]
+    async fn test_render_metrics() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let handle = make_worker(
+            token.clone(),
+            None,
+            |_| async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                LoopState::Duration(Duration::from_secs(60))
+            },
+            || async {},
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = find_status(handle.id()).map(|it| lock(&it).id).unwrap();
+        let text = render_metrics();
+        token.cancel();
+        handle.await?;
+
+        let labels = format!("task=\"worker-{}\",kind=\"worker\"", id);
+        for line in [
+            format!("resident_task_runs_total{{{}}} 1", labels),
+            format!(
+                "resident_task_outcomes_total{{{},state=\"duration\"}} 1",
+                labels
+            ),
+            format!(
+                "resident_task_outcomes_total{{{},state=\"continue\"}} 0",
+                labels
+            ),
+            format!(
+                "resident_task_duration_seconds_bucket{{{},le=\"0.01\"}} 0",
+                labels
+            ),
+            format!(
+                "resident_task_duration_seconds_bucket{{{},le=\"+Inf\"}} 1",
+                labels
+            ),
+            format!("resident_task_pool_errors_total{{{}}} 0", labels),
+            "# TYPE resident_task_duration_seconds histogram".to_owned(),
+        ] {
+            assert!(text.lines().any(|it| it == line), "{}\n{}", line, text);
+        }
+        Ok(())
+    }
+}
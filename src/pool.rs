@@ -0,0 +1,419 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    spawn,
+    sync::Notify,
+    task::{AbortHandle, JoinHandle, JoinSet},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, Instrument};
+
+use crate::{resource::Resource, runner::spawn_worker, LoopConfig, LoopState, TaskContext};
+
+///
+/// PoolSize
+///   min: number of instances always running, at least 1,
+///     an instance stopped by a panic is replaced while the pool is running
+///   max: upper bound of the instances, an instance is added while every instance
+///     returns LoopState::Continue and removed when it returns another state
+///   a number makes the pool of the fixed size
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSize {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl From<usize> for PoolSize {
+    fn from(concurrency: usize) -> Self {
+        Self {
+            min: concurrency,
+            max: concurrency,
+        }
+    }
+}
+
+///
+/// Runs instances of the same worker which share the resource and the token
+///   each instance is a worker of make_worker_with_config named "{name}-{n}",
+///   LoopState::Terminate stops every instance and LoopState::AllTerminate also cancels the token.
+///   stop_function is called once after every instance has stopped.
+///
+pub fn make_worker_pool<R, Fut1, Fut2>(
+    size: impl Into<PoolSize>,
+    resource: R,
+    token: CancellationToken,
+    stop_check_duration: impl Into<Option<Duration>>,
+    config: LoopConfig,
+    task_function: impl Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    stop_function: impl Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+) -> JoinHandle<()>
+where
+    R: Resource + Clone,
+    Fut1: Future<Output = LoopState> + Send,
+    Fut2: Future<Output = ()> + Send,
+{
+    let size = size.into();
+    let min = size.min.max(1);
+    let pool = Arc::new(Pool {
+        min,
+        max: size.max.max(min),
+        running: AtomicUsize::new(0),
+        busy: AtomicUsize::new(0),
+        scale_up: Notify::new(),
+    });
+    let stop_check_duration = stop_check_duration.into();
+    let task_function = Arc::new(task_function);
+    let pool_span = info_span!("pool", name = %config.name);
+    spawn(
+        async move {
+            let pool_token = token.child_token();
+            let mut instances = Instances::default();
+            let mut index = 0;
+            let mut start = |instances: &mut Instances| {
+                index += 1;
+                let name = if config.name.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}-{}", config.name, index)
+                };
+                debug!(name, "start pool instance");
+                pool.running.fetch_add(1, Ordering::SeqCst);
+                instances.spawn(spawn_instance(
+                    &pool,
+                    &token,
+                    &pool_token,
+                    stop_check_duration,
+                    LoopConfig {
+                        name,
+                        ..config.clone()
+                    },
+                    resource.clone(),
+                    task_function.clone(),
+                ));
+            };
+            for _ in 0..pool.min {
+                start(&mut instances);
+            }
+
+            let mut replace = false;
+            // 全て止まり、補充もしなければ終わる
+            while !instances.set.is_empty() || replace {
+                tokio::select! {
+                    Some(res) = instances.set.join_next() => {
+                        let (retired, busy) = res.unwrap_or((false, false));
+                        // 縮小で止まったものは数え済み
+                        if !retired {
+                            pool.running.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        // パニックで止まった場合も、直近がContinueだった数から除く
+                        if busy {
+                            pool.busy.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        // パニックなどで最小を下回ったら補充する
+                        replace = !pool_token.is_cancelled()
+                            && pool.running.load(Ordering::SeqCst) < pool.min;
+                    }
+                    _ = pool.scale_up.notified() => {
+                        if !pool_token.is_cancelled() && pool.running.load(Ordering::SeqCst) < pool.max {
+                            start(&mut instances);
+                        }
+                    }
+                    // パニックが続く場合に詰めて起動し直さない
+                    _ = sleep(REPLACE_DELAY), if replace => {
+                        replace = false;
+                        while !pool_token.is_cancelled() && pool.running.load(Ordering::SeqCst) < pool.min {
+                            debug!("replace stopped pool instance");
+                            start(&mut instances);
+                        }
+                    }
+                }
+            }
+            stop_function(resource.acquire().await).await;
+        }
+        .instrument(pool_span),
+    )
+}
+
+// 止まったインスタンスを補充するまでの時間
+const REPLACE_DELAY: Duration = Duration::from_secs(1);
+
+// インスタンス数と、直近の結果がContinueだったインスタンス数
+struct Pool {
+    min: usize,
+    max: usize,
+    running: AtomicUsize,
+    busy: AtomicUsize,
+    scale_up: Notify,
+}
+
+impl Pool {
+    // 実行結果から増減を決める、縮小して止める場合はtrue
+    fn observe(&self, state: &LoopState, busy: &AtomicBool) -> bool {
+        if *state == LoopState::Continue {
+            if !busy.swap(true, Ordering::SeqCst) {
+                self.busy.fetch_add(1, Ordering::SeqCst);
+            }
+            let running = self.running.load(Ordering::SeqCst);
+            if self.busy.load(Ordering::SeqCst) >= running && running < self.max {
+                self.scale_up.notify_one();
+            }
+            return false;
+        }
+        if busy.swap(false, Ordering::SeqCst) {
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (running > self.min).then(|| running - 1)
+            })
+            .is_ok()
+    }
+}
+
+// 止まったインスタンスの縮小で止まったかどうかと、直近の結果がContinueだったかどうか
+// プールが中断されたらインスタンスも中断する
+#[derive(Default)]
+struct Instances {
+    set: JoinSet<(bool, bool)>,
+    aborts: Vec<AbortHandle>,
+}
+
+impl Instances {
+    fn spawn(
+        &mut self,
+        (handle, retired, busy): (JoinHandle<()>, Arc<AtomicBool>, Arc<AtomicBool>),
+    ) {
+        self.aborts.retain(|it| !it.is_finished());
+        self.aborts.push(handle.abort_handle());
+        self.set.spawn(async move {
+            let _ = handle.await;
+            (
+                retired.load(Ordering::SeqCst),
+                busy.swap(false, Ordering::SeqCst),
+            )
+        });
+    }
+}
+
+impl Drop for Instances {
+    fn drop(&mut self) {
+        for abort in &self.aborts {
+            abort.abort();
+        }
+    }
+}
+
+// 1つのインスタンスを動かす、縮小で止まったかどうかと直近がContinueだったかどうかも返す
+fn spawn_instance<R, Task, Fut1>(
+    pool: &Arc<Pool>,
+    token: &CancellationToken,
+    pool_token: &CancellationToken,
+    stop_check_duration: Option<Duration>,
+    config: LoopConfig,
+    resource: R,
+    task_function: Arc<Task>,
+) -> (JoinHandle<()>, Arc<AtomicBool>, Arc<AtomicBool>)
+where
+    R: Resource,
+    Task: Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    Fut1: Future<Output = LoopState> + Send,
+{
+    let pool = pool.clone();
+    let token = token.clone();
+    let pool_token = pool_token.clone();
+    let busy = Arc::new(AtomicBool::new(false));
+    let retired = Arc::new(AtomicBool::new(false));
+    let task_retired = retired.clone();
+    let task_busy = busy.clone();
+    let handle = spawn_worker(
+        pool_token.child_token(),
+        stop_check_duration,
+        config,
+        resource,
+        move |context, resource, instance_token| {
+            let future = task_function(context, resource, instance_token);
+            let pool = pool.clone();
+            let token = token.clone();
+            let pool_token = pool_token.clone();
+            let busy = task_busy.clone();
+            let retired = task_retired.clone();
+            async move {
+                let state = future.await;
+                match state {
+                    LoopState::AllTerminate => token.cancel(),
+                    LoopState::Terminate => pool_token.cancel(),
+                    _ => {
+                        if pool.observe(&state, &busy) {
+                            debug!("retire pool instance");
+                            retired.store(true, Ordering::SeqCst);
+                            return LoopState::Terminate;
+                        }
+                    }
+                }
+                state
+            }
+        },
+        |_| async {},
+    );
+    (handle, retired, busy)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::status::task_statuses;
+
+    // 名前が一致する動いているインスタンスの数
+    fn instances(name: &str) -> usize {
+        task_statuses()
+            .iter()
+            .filter(|it| it.name.starts_with(name) && it.exit.is_none())
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_worker_pool() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let queue = Arc::new(Mutex::new(20_u32));
+        let task_queue = queue.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pool_stopped = stopped.clone();
+        let handle = make_worker_pool(
+            PoolSize { min: 1, max: 4 },
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                name: "test_worker_pool".to_owned(),
+                ..Default::default()
+            },
+            move |_, _, _| {
+                let queue = task_queue.clone();
+                async move {
+                    let found = {
+                        let mut queue = queue.lock().unwrap();
+                        let found = *queue > 0;
+                        *queue = (*queue).saturating_sub(1);
+                        found
+                    };
+                    if !found {
+                        return LoopState::Duration(Duration::from_secs(10));
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    LoopState::Continue
+                }
+            },
+            move |_| {
+                let stopped = pool_stopped.clone();
+                async move { stopped.store(true, Ordering::SeqCst) }
+            },
+        );
+
+        // 処理が続く間は最大まで増え、なくなれば最小まで減る
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        assert_eq!(instances("test_worker_pool-"), 4);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(*queue.lock().unwrap(), 0);
+        assert_eq!(instances("test_worker_pool-"), 1);
+
+        token.cancel();
+        handle.await?;
+        assert_eq!(instances("test_worker_pool-"), 0);
+        assert!(stopped.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_worker_pool_replace() -> anyhow::Result<()> {
+        // 最初の2回の実行がパニックしても最小数を保つ
+        let token = CancellationToken::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pool_stopped = stopped.clone();
+        let handle = make_worker_pool(
+            PoolSize { min: 2, max: 2 },
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                name: "test_worker_pool_replace".to_owned(),
+                ..Default::default()
+            },
+            move |_, _, _| {
+                let run = task_runs.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if run < 2 {
+                        panic!("boom");
+                    }
+                    LoopState::Duration(Duration::from_secs(10))
+                }
+            },
+            move |_| {
+                let stopped = pool_stopped.clone();
+                async move { stopped.store(true, Ordering::SeqCst) }
+            },
+        );
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(instances("test_worker_pool_replace-"), 2);
+        assert!(runs.load(Ordering::SeqCst) >= 4);
+        assert!(!stopped.load(Ordering::SeqCst));
+
+        token.cancel();
+        handle.await?;
+        assert!(stopped.load(Ordering::SeqCst));
+
+        // Continueの後にパニックしたインスタンスは、補充後に忙しい数として残らない
+        let token = CancellationToken::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let handle = make_worker_pool(
+            PoolSize { min: 2, max: 3 },
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                name: "test_worker_pool_replace_busy".to_owned(),
+                ..Default::default()
+            },
+            move |_, _, _| {
+                let run = task_runs.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match run {
+                        // 1秒後にContinueを返し、続けた実行でパニックする
+                        0 | 3 => {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            LoopState::Continue
+                        }
+                        2 => panic!("boom"),
+                        _ => {
+                            tokio::time::sleep(Duration::from_secs(2)).await;
+                            LoopState::Duration(Duration::from_secs(60))
+                        }
+                    }
+                }
+            },
+            |_| async {},
+        );
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 5);
+        assert_eq!(instances("test_worker_pool_replace_busy-"), 2);
+
+        token.cancel();
+        handle.await?;
+        Ok(())
+    }
+}
@@ -3,18 +3,37 @@ use std::{future::Future, time::Duration};
 
 use chrono::prelude::*;
 use cron::Schedule;
-use tokio::{spawn, task::JoinHandle};
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-use crate::{execute_sleep, LoopState};
+use crate::{
+    make_looper_with_config, make_worker_with_config, resource::Resource, LoopConfig, LoopState,
+};
 
+pub mod advisory_lock;
 pub mod holder;
+pub mod listen;
+
+pub use advisory_lock::AdvisoryLock;
+pub use listen::{make_listen_worker, ListenConfig};
+
+impl Resource for deadpool_postgres::Pool {
+    type Output = Result<deadpool_postgres::Client, deadpool_postgres::PoolError>;
+
+    fn acquire(&self) -> impl Future<Output = Self::Output> + Send {
+        self.get()
+    }
+
+    fn is_err(output: &Self::Output) -> bool {
+        output.is_err()
+    }
+}
 
 pub fn make_looper<Fut1, Fut2>(
     pg_pool: deadpool_postgres::Pool,
     token: CancellationToken,
     schedule: Schedule,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(
             DateTime<Utc>,
             Result<deadpool_postgres::Client, deadpool_postgres::PoolError>,
@@ -29,47 +48,24 @@ pub fn make_looper<Fut1, Fut2>(
         + 'static,
 ) -> JoinHandle<()>
 where
-    Fut1: Future<Output = LoopState> + Send,
+    Fut1: Future<Output = LoopState> + Send + 'static,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        let mut next_tick: DateTime<Utc> = match schedule.upcoming(Utc).next() {
-            Some(next_tick) => next_tick,
-            None => {
-                stop_function(pg_pool.get().await).await;
-                return;
-            }
-        };
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function(pg_pool.get().await).await;
-                break;
-            }
-
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now, pg_pool.get().await, token.clone())
-                    .await
-                    .looper(&token, &now, &schedule)
-                {
-                    next_tick = res;
-                } else {
-                    stop_function(pg_pool.get().await).await;
-                    break;
-                }
-            }
-
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+    make_looper_with_config(
+        pg_pool,
+        token,
+        schedule,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, pg_client, token| task_function(context.started, pg_client, token),
+        stop_function,
+    )
 }
 
 pub fn make_worker<Fut1, Fut2>(
     pg_pool: deadpool_postgres::Pool,
     token: CancellationToken,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(
             DateTime<Utc>,
             Result<deadpool_postgres::Client, deadpool_postgres::PoolError>,
@@ -87,32 +83,12 @@ where
     Fut1: Future<Output = LoopState> + Send,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        // 動き出した瞬間は実行する
-        let mut next_tick: DateTime<Utc> = Utc::now();
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function(pg_pool.get().await).await;
-                break;
-            }
-
-            // 現在時間と次実行する処理の時間をチェックする
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now, pg_pool.get().await, token.clone())
-                    .await
-                    .worker(&token, &now)
-                {
-                    next_tick = res;
-                } else {
-                    stop_function(pg_pool.get().await).await;
-                    break;
-                }
-            }
-
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+    make_worker_with_config(
+        pg_pool,
+        token,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, pg_client, token| task_function(context.started, pg_client, token),
+        stop_function,
+    )
 }
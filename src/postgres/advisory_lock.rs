@@ -0,0 +1,95 @@
+use futures_util::future::BoxFuture;
+use tracing::{debug, warn};
+
+use crate::lock::{TaskLock, TaskLockGuard};
+
+///
+/// AdvisoryLock
+///   TaskLock for LoopConfig lock which takes pg_try_advisory_lock keyed by the name of the task,
+///   so a looper given the same name on every replica is executed by one of them at each tick.
+///   LoopConfig name is required, without it the key is "looper-{id}" which differs between processes.
+///   The lock is held by one more connection of pg_pool while the task runs.
+///   A replica whose tick comes after the lock is released runs the tick again,
+///   so the clocks of the replicas should be closer than the duration of the task.
+///
+#[derive(Debug, Clone)]
+pub struct AdvisoryLock {
+    pg_pool: deadpool_postgres::Pool,
+}
+
+impl AdvisoryLock {
+    pub fn new(pg_pool: deadpool_postgres::Pool) -> Self {
+        Self { pg_pool }
+    }
+}
+
+impl TaskLock for AdvisoryLock {
+    // 他で持っている場合やエラーの場合はNone
+    fn try_lock<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<Box<dyn TaskLockGuard>>> {
+        Box::pin(async move {
+            let pg_client = match self.pg_pool.get().await {
+                Ok(pg_client) => pg_client,
+                Err(err) => {
+                    warn!(error = %err, "advisory lock pool error");
+                    return None;
+                }
+            };
+            let res = pg_client
+                .query_one(
+                    "SELECT pg_try_advisory_lock(hashtextextended($1, 0))",
+                    &[&name],
+                )
+                .await;
+            match res.and_then(|row| row.try_get::<_, bool>(0)) {
+                Ok(true) => Some(Box::new(AdvisoryLockGuard {
+                    pg_client: Some(pg_client),
+                    name: name.to_owned(),
+                }) as Box<dyn TaskLockGuard>),
+                Ok(false) => {
+                    debug!(name, "skip tick locked by another replica");
+                    None
+                }
+                Err(err) => {
+                    warn!(error = %err, "advisory lock error");
+                    None
+                }
+            }
+        })
+    }
+}
+
+// アドバイザリーロックを持った接続
+// 解放しないまま落とされた場合は、接続を切ってロックを解放させる
+struct AdvisoryLockGuard {
+    pg_client: Option<deadpool_postgres::Client>,
+    name: String,
+}
+
+impl TaskLockGuard for AdvisoryLockGuard {
+    fn unlock(mut self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let Some(pg_client) = &self.pg_client else {
+                return;
+            };
+            match pg_client
+                .execute(
+                    "SELECT pg_advisory_unlock(hashtextextended($1, 0))",
+                    &[&self.name],
+                )
+                .await
+            {
+                // 解放できたらプールに戻す
+                Ok(_) => drop(self.pg_client.take()),
+                Err(err) => warn!(error = %err, "advisory unlock error"),
+            }
+        })
+    }
+}
+
+impl Drop for AdvisoryLockGuard {
+    fn drop(&mut self) {
+        if let Some(pg_client) = self.pg_client.take() {
+            drop(deadpool_postgres::Client::take(pg_client));
+        }
+    }
+}
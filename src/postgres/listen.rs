@@ -0,0 +1,227 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use deadpool_postgres::tokio_postgres::{
+    self,
+    tls::{MakeTlsConnect, TlsConnect},
+    AsyncMessage, Notification, Socket,
+};
+use futures_util::{stream::poll_fn, StreamExt};
+use tokio::{
+    spawn,
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{make_worker_with_config, LoopConfig, LoopState};
+
+///
+/// ListenConfig
+///   channels: channels to LISTEN on
+///   poll_interval: the task runs at least this often without notifications,
+///     LoopState::Duration returned by the task is shortened to this
+///   reconnect_interval: time to wait before reconnecting after the connection is lost
+///
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    pub channels: Vec<String>,
+    pub poll_interval: Duration,
+    pub reconnect_interval: Duration,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec![],
+            poll_interval: Duration::from_secs(60),
+            reconnect_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+///
+/// Worker woken by LISTEN/NOTIFY
+///   pg_config and tls are used for the dedicated connection which LISTENs,
+///   the task gets a client from pg_pool as make_worker.
+///   The notification is passed to the task, None when run by polling.
+///   Notifications received while the connection is lost are missed,
+///   so the task runs once after reconnecting.
+///   config is applied to the worker as make_worker_with_config,
+///   its wake is also notified by the notifications.
+///
+#[allow(clippy::too_many_arguments)]
+pub fn make_listen_worker<T, Fut1, Fut2>(
+    pg_config: tokio_postgres::Config,
+    tls: T,
+    pg_pool: deadpool_postgres::Pool,
+    token: CancellationToken,
+    listen_config: ListenConfig,
+    config: LoopConfig,
+    task_function: impl Fn(
+            DateTime<Utc>,
+            Option<Notification>,
+            Result<deadpool_postgres::Client, deadpool_postgres::PoolError>,
+            CancellationToken,
+        ) -> Fut1
+        + Send
+        + Sync
+        + 'static,
+    stop_function: impl Fn(Result<deadpool_postgres::Client, deadpool_postgres::PoolError>) -> Fut2
+        + Send
+        + Sync
+        + 'static,
+) -> JoinHandle<()>
+where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+    T::Stream: Send + Sync,
+    T::TlsConnect: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    Fut1: Future<Output = LoopState> + Send,
+    Fut2: Future<Output = ()> + Send,
+{
+    let wake = config.wake.clone().unwrap_or_default();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let listen_token = token.child_token();
+    spawn(listen(
+        pg_config,
+        tls,
+        listen_config.channels,
+        listen_config.reconnect_interval,
+        listen_token.clone(),
+        sender,
+        wake.clone(),
+    ));
+
+    let poll_interval = listen_config.poll_interval;
+    let task_wake = wake.clone();
+    make_worker_with_config(
+        pg_pool,
+        token,
+        None,
+        LoopConfig {
+            wake: Some(wake),
+            ..config
+        },
+        move |context, pg_client, token| {
+            let notification = {
+                let mut receiver = receiver.lock().unwrap_or_else(|err| err.into_inner());
+                let notification = receiver.try_recv().ok();
+                // 残りがあれば続けて実行する
+                if !receiver.is_empty() {
+                    task_wake.notify_one();
+                }
+                notification
+            };
+            let future = task_function(context.started, notification, pg_client, token);
+            async move {
+                match future.await {
+                    LoopState::Duration(duration) => {
+                        LoopState::Duration(duration.min(poll_interval))
+                    }
+                    state => state,
+                }
+            }
+        },
+        move |pg_client| {
+            listen_token.cancel();
+            stop_function(pg_client)
+        },
+    )
+}
+
+// 専用の接続でLISTENし、通知を受け取ったらワーカーを起こす
+async fn listen<T>(
+    pg_config: tokio_postgres::Config,
+    tls: T,
+    channels: Vec<String>,
+    reconnect_interval: Duration,
+    token: CancellationToken,
+    sender: mpsc::UnboundedSender<Notification>,
+    wake: Arc<Notify>,
+) where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+    T::Stream: Send + Sync,
+    T::TlsConnect: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let mut connected_once = false;
+    while !token.is_cancelled() {
+        tokio::select! {
+            res = listen_once(&pg_config, tls.clone(), &channels, &sender, &wake, &mut connected_once) => {
+                if let Err(err) = res {
+                    warn!(error = %err, "listen connection error");
+                }
+            }
+            _ = token.cancelled() => break,
+        }
+        tokio::select! {
+            _ = sleep(reconnect_interval) => {}
+            _ = token.cancelled() => {}
+        }
+    }
+}
+
+// 接続が切れるまで通知を受け取る
+async fn listen_once<T>(
+    pg_config: &tokio_postgres::Config,
+    tls: T,
+    channels: &[String],
+    sender: &mpsc::UnboundedSender<Notification>,
+    wake: &Notify,
+    connected_once: &mut bool,
+) -> Result<(), tokio_postgres::Error>
+where
+    T: MakeTlsConnect<Socket> + Clone + Send + Sync + 'static,
+    T::Stream: Send + Sync,
+    T::TlsConnect: Send + Sync,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    let (client, mut connection) = pg_config.connect(tls).await?;
+    let (message_sender, mut message_receiver) = mpsc::unbounded_channel();
+    let driver = spawn(async move {
+        let mut messages = poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    let _ = message_sender.send(notification);
+                }
+                Ok(_) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    });
+
+    let query: String = channels
+        .iter()
+        .map(|channel| format!("LISTEN \"{}\";", channel.replace('"', "\"\"")))
+        .collect();
+    client.batch_execute(&query).await?;
+    debug!(channels = ?channels, "listen started");
+
+    // 切れていた間の通知は受け取れないので、再接続したら一度実行する
+    if *connected_once {
+        wake.notify_one();
+    }
+    *connected_once = true;
+
+    while let Some(notification) = message_receiver.recv().await {
+        debug!(
+            channel = notification.channel(),
+            payload = notification.payload(),
+            "notification received"
+        );
+        let _ = sender.send(notification);
+        wake.notify_one();
+    }
+    drop(client);
+    driver.await.unwrap_or(Ok(()))
+}
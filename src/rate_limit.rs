@@ -0,0 +1,173 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+
+///
+/// RateLimiter
+///   token bucket limiting the executions of loopers and workers
+///   limit: executions allowed per interval
+///   per: the interval
+///   burst: executions allowed at once after an idle time, the size of the bucket
+///   clones share the bucket, e.g. between workers calling the same API
+///
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    per: Duration,
+    burst: u32,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Option<DateTime<Utc>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, per: Duration, burst: u32) -> Self {
+        let burst = burst.max(1);
+        Self {
+            limit: limit.max(1),
+            per,
+            burst,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: burst as f64,
+                updated: None,
+            })),
+        }
+    }
+
+    ///
+    /// Executions allowed now without waiting
+    ///
+    pub fn remaining(&self, now: DateTime<Utc>) -> u32 {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        self.refill(&mut bucket, now);
+        bucket.tokens as u32
+    }
+
+    // 1回分を使って残りを返す、使い切っている場合は次に使える時間
+    pub(crate) fn take(&self, now: DateTime<Utc>) -> Result<u32, DateTime<Utc>> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        self.refill(&mut bucket, now);
+        // 浮動小数点の誤差で待ち続けないようにする
+        if bucket.tokens + 1e-9 >= 1.0 {
+            bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+            return Ok(bucket.tokens as u32);
+        }
+        let wait = (1.0 - bucket.tokens) / self.rate();
+        Err(now + Duration::from_secs_f64(wait))
+    }
+
+    // 1秒あたりに増える回数
+    fn rate(&self) -> f64 {
+        self.limit as f64 / self.per.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: DateTime<Utc>) {
+        if let Some(updated) = bucket.updated {
+            let elapsed = (now - updated).to_std().unwrap_or_default().as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate()).min(self.burst as f64);
+        }
+        bucket.updated = Some(bucket.updated.map_or(now, |updated| updated.max(now)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cron::Schedule;
+
+    use super::*;
+    use crate::{
+        clock::MockClock, make_looper_with_config, make_worker_with_config, LoopConfig, LoopState,
+        MisfirePolicy,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() -> anyhow::Result<()> {
+        // 1秒に2回まで、まとめては3回まで
+        let token = tokio_util::sync::CancellationToken::new();
+        let executions = Arc::new(Mutex::new(vec![]));
+        let task_executions = executions.clone();
+        let rate_limit = RateLimiter::new(2, Duration::from_secs(1), 3);
+        let handle = make_worker_with_config(
+            (),
+            token.clone(),
+            None,
+            LoopConfig {
+                rate_limit: Some(rate_limit.clone()),
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                let mut executions = task_executions.lock().unwrap();
+                executions.push((context.started.timestamp_millis(), context.budget));
+                let state = if executions.len() < 7 {
+                    LoopState::Continue
+                } else {
+                    LoopState::Terminate
+                };
+                async move { state }
+            },
+            |_| async {},
+        );
+        handle.await?;
+        assert_eq!(
+            *executions.lock().unwrap(),
+            vec![
+                (0, Some(2)),
+                (0, Some(1)),
+                (0, Some(0)),
+                (500, Some(0)),
+                (1000, Some(0)),
+                (1500, Some(0)),
+                (2000, Some(0)),
+            ]
+        );
+        assert_eq!(rate_limit.remaining(Utc.timestamp_opt(4, 0).unwrap()), 3);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_looper() -> anyhow::Result<()> {
+        // 2秒ごとの予定を3秒に1回までに制限する
+        // 遅れて実行してもscheduledは予定の時刻のままで、遅れた間の時刻はMisfirePolicyに従う
+        let token = tokio_util::sync::CancellationToken::new();
+        let executions = Arc::new(Mutex::new(vec![]));
+        let task_executions = executions.clone();
+        let handle = make_looper_with_config(
+            (),
+            token.clone(),
+            Schedule::from_str("*/2 * * * * *")?,
+            None,
+            LoopConfig {
+                misfire: MisfirePolicy::FireOnce,
+                rate_limit: Some(RateLimiter::new(1, Duration::from_secs(3), 1)),
+                clock: Arc::new(MockClock::new(Utc.timestamp_opt(0, 0).unwrap())),
+                ..Default::default()
+            },
+            move |context, _, _| {
+                task_executions
+                    .lock()
+                    .unwrap()
+                    .push((context.scheduled.timestamp(), context.started.timestamp()));
+                async { LoopState::Continue }
+            },
+            |_| async {},
+        );
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        token.cancel();
+        handle.await?;
+        assert_eq!(
+            *executions.lock().unwrap(),
+            vec![(2, 2), (4, 5), (8, 8), (10, 11), (14, 14)]
+        );
+        Ok(())
+    }
+}
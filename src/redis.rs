@@ -4,16 +4,34 @@ use std::{future::Future, time::Duration};
 
 use chrono::prelude::*;
 use cron::Schedule;
-use tokio::{spawn, task::JoinHandle};
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 
-use crate::{execute_sleep, LoopState};
+pub mod subscribe;
+
+pub use subscribe::{make_subscribe_worker, SubscribeConfig, SubscribeMessage, SubscribeSource};
+
+use crate::{
+    make_looper_with_config, make_worker_with_config, resource::Resource, LoopConfig, LoopState,
+};
+
+impl Resource for deadpool_redis::Pool {
+    type Output = Result<deadpool_redis::Connection, deadpool_redis::PoolError>;
+
+    fn acquire(&self) -> impl Future<Output = Self::Output> + Send {
+        self.get()
+    }
+
+    fn is_err(output: &Self::Output) -> bool {
+        output.is_err()
+    }
+}
 
 pub fn make_looper<Fut1, Fut2>(
     redis_pool: deadpool_redis::Pool,
     token: CancellationToken,
     schedule: Schedule,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(
             DateTime<Utc>,
             Result<deadpool_redis::Connection, deadpool_redis::PoolError>,
@@ -28,47 +46,24 @@ pub fn make_looper<Fut1, Fut2>(
         + 'static,
 ) -> JoinHandle<()>
 where
-    Fut1: Future<Output = LoopState> + Send,
+    Fut1: Future<Output = LoopState> + Send + 'static,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        let mut next_tick: DateTime<Utc> = match schedule.upcoming(Utc).next() {
-            Some(next_tick) => next_tick,
-            None => {
-                stop_function(redis_pool.get().await).await;
-                return;
-            }
-        };
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function(redis_pool.get().await).await;
-                break;
-            }
-
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now, redis_pool.get().await, token.clone())
-                    .await
-                    .looper(&token, &now, &schedule)
-                {
-                    next_tick = res;
-                } else {
-                    stop_function(redis_pool.get().await).await;
-                    break;
-                }
-            }
-
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+    make_looper_with_config(
+        redis_pool,
+        token,
+        schedule,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, redis_conn, token| task_function(context.started, redis_conn, token),
+        stop_function,
+    )
 }
 
 pub fn make_worker<Fut1, Fut2>(
     redis_pool: deadpool_redis::Pool,
     token: CancellationToken,
-    stop_check_duration: Duration,
+    stop_check_duration: impl Into<Option<Duration>>,
     task_function: impl Fn(
             DateTime<Utc>,
             Result<deadpool_redis::Connection, deadpool_redis::PoolError>,
@@ -86,32 +81,12 @@ where
     Fut1: Future<Output = LoopState> + Send,
     Fut2: Future<Output = ()> + Send,
 {
-    spawn(async move {
-        // 動き出した瞬間は実行する
-        let mut next_tick: DateTime<Utc> = Utc::now();
-        loop {
-            // グレースフルストップのチェック
-            if token.is_cancelled() {
-                stop_function(redis_pool.get().await).await;
-                break;
-            }
-
-            // 現在時間と次実行する処理の時間をチェックする
-            let now = Utc::now();
-            if now >= next_tick {
-                // 定期的に行う処理実行
-                if let Some(res) = task_function(now, redis_pool.get().await, token.clone())
-                    .await
-                    .worker(&token, &now)
-                {
-                    next_tick = res;
-                } else {
-                    stop_function(redis_pool.get().await).await;
-                    break;
-                }
-            }
-
-            execute_sleep(&stop_check_duration, &next_tick, &now).await;
-        }
-    })
+    make_worker_with_config(
+        redis_pool,
+        token,
+        stop_check_duration,
+        LoopConfig::default(),
+        move |context, redis_conn, token| task_function(context.started, redis_conn, token),
+        stop_function,
+    )
 }
@@ -0,0 +1,424 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use deadpool_redis::redis;
+use futures_util::StreamExt;
+use thiserror::Error;
+use tokio::{
+    spawn,
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{make_worker_with_config, LoopConfig, LoopState};
+
+///
+/// SubscribeSource
+///   BlPop: pops the head of the lists with BLPOP
+///   BrPop: pops the tail of the lists with BRPOP
+///   Channels: subscribes the pub/sub channels with a dedicated connection made by the client
+///
+#[derive(Debug, Clone)]
+pub enum SubscribeSource {
+    BlPop(Vec<String>),
+    BrPop(Vec<String>),
+    Channels {
+        client: redis::Client,
+        channels: Vec<String>,
+    },
+}
+
+///
+/// SubscribeConfig
+///   source: where the messages come from
+///   block_timeout: timeout of each BLPOP/BRPOP, the pop is repeated until a message arrives
+///   reconnect_interval: time to wait before reconnecting after a redis error
+///
+#[derive(Debug, Clone)]
+pub struct SubscribeConfig {
+    pub source: SubscribeSource,
+    pub block_timeout: Duration,
+    pub reconnect_interval: Duration,
+}
+
+impl SubscribeConfig {
+    pub fn new(source: SubscribeSource) -> Self {
+        Self {
+            source,
+            block_timeout: Duration::from_secs(5),
+            reconnect_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+///
+/// SubscribeMessage
+///   key: list key popped from or channel name
+///   payload: popped value or published message
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeMessage {
+    pub key: String,
+    pub payload: String,
+}
+
+///
+/// Worker woken by a message from redis
+///   a background task waits for the messages of the source and wakes the worker,
+///   the task is executed once per message with a connection from redis_pool.
+///   config is applied to the worker as make_worker_with_config and its wake is
+///   also notified by the messages. The worker is idle while waiting,
+///   so timeout and pause of config and the health check apply to the handling of each message.
+///   Redis errors are logged and the connection is made again after reconnect_interval.
+///   Stopping waits for the pop in progress, at most block_timeout,
+///   and a popped message not handled yet is pushed back to its list.
+///   Pub/sub messages published while the connection is lost are missed.
+///
+pub fn make_subscribe_worker<Fut1, Fut2>(
+    redis_pool: deadpool_redis::Pool,
+    token: CancellationToken,
+    subscribe_config: SubscribeConfig,
+    config: LoopConfig,
+    task_function: impl Fn(
+            DateTime<Utc>,
+            SubscribeMessage,
+            Result<deadpool_redis::Connection, deadpool_redis::PoolError>,
+            CancellationToken,
+        ) -> Fut1
+        + Send
+        + Sync
+        + 'static,
+    stop_function: impl Fn(Result<deadpool_redis::Connection, deadpool_redis::PoolError>) -> Fut2
+        + Send
+        + Sync
+        + 'static,
+) -> JoinHandle<()>
+where
+    Fut1: Future<Output = LoopState> + Send,
+    Fut2: Future<Output = ()> + Send,
+{
+    let wake = config.wake.clone().unwrap_or_default();
+    let subscribe_token = token.child_token();
+    let SubscribeConfig {
+        source,
+        block_timeout,
+        reconnect_interval,
+    } = subscribe_config;
+    let (receiver, feeder) = match source {
+        SubscribeSource::BlPop(keys) => spawn_pop(
+            &redis_pool,
+            Pop::Left,
+            keys,
+            block_timeout,
+            reconnect_interval,
+            &subscribe_token,
+            &wake,
+        ),
+        SubscribeSource::BrPop(keys) => spawn_pop(
+            &redis_pool,
+            Pop::Right,
+            keys,
+            block_timeout,
+            reconnect_interval,
+            &subscribe_token,
+            &wake,
+        ),
+        SubscribeSource::Channels { client, channels } => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let feeder = spawn(subscribe(
+                client,
+                channels,
+                reconnect_interval,
+                subscribe_token.clone(),
+                sender,
+                wake.clone(),
+            ));
+            (Receiver::Channels(receiver), feeder)
+        }
+    };
+    let receiver = Arc::new(Mutex::new(receiver));
+    let feeder = Arc::new(Mutex::new(Some(feeder)));
+    let task_pool = redis_pool.clone();
+    let task_receiver = receiver.clone();
+    let task_wake = wake.clone();
+    let task_function = Arc::new(task_function);
+    let stop_function = Arc::new(stop_function);
+
+    make_worker_with_config(
+        (),
+        token,
+        None,
+        LoopConfig {
+            wake: Some(wake),
+            ..config
+        },
+        move |context, _, token| {
+            let message = {
+                let mut receiver = lock(&task_receiver);
+                let message = receiver.try_recv();
+                // 残りがあれば続けて実行する
+                if !receiver.is_empty() {
+                    task_wake.notify_one();
+                }
+                message
+            };
+            let redis_pool = task_pool.clone();
+            let task_function = task_function.clone();
+            async move {
+                // 届いていなければ起こされるまで待つ
+                let Some(message) = message else {
+                    return LoopState::Duration(IDLE_INTERVAL);
+                };
+                let redis_connection = redis_pool.get().await;
+                task_function(context.started, message, redis_connection, token).await
+            }
+        },
+        move |_| {
+            subscribe_token.cancel();
+            let feeder = lock(&feeder).take();
+            let receiver = receiver.clone();
+            let redis_pool = redis_pool.clone();
+            let stop_function = stop_function.clone();
+            async move {
+                if let Some(feeder) = feeder {
+                    let _ = feeder.await;
+                }
+                let pending = lock(&receiver).take_popped();
+                if let Some((pop, message)) = pending {
+                    push_back(&redis_pool, pop, message).await;
+                }
+                stop_function(redis_pool.get().await).await
+            }
+        },
+    )
+}
+
+// メッセージが無い間に、動いていることを示すために実行する間隔
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("RedisPool {0}")]
+    RedisPool(#[from] deadpool_redis::PoolError),
+
+    #[error("Redis {0}")]
+    Redis(#[from] redis::RedisError),
+}
+
+// BLPOPかBRPOPか
+#[derive(Debug, Clone, Copy)]
+enum Pop {
+    Left,
+    Right,
+}
+
+impl Pop {
+    fn command(self) -> &'static str {
+        match self {
+            Pop::Left => "BLPOP",
+            Pop::Right => "BRPOP",
+        }
+    }
+
+    // 取り出した側に戻すコマンド
+    fn push_command(self) -> &'static str {
+        match self {
+            Pop::Left => "LPUSH",
+            Pop::Right => "RPUSH",
+        }
+    }
+}
+
+// 届いたメッセージ、取り出したメッセージは1つだけ持つ
+enum Receiver {
+    Pop(Pop, mpsc::Receiver<SubscribeMessage>),
+    Channels(mpsc::UnboundedReceiver<SubscribeMessage>),
+}
+
+impl Receiver {
+    fn try_recv(&mut self) -> Option<SubscribeMessage> {
+        match self {
+            Receiver::Pop(_, receiver) => receiver.try_recv().ok(),
+            Receiver::Channels(receiver) => receiver.try_recv().ok(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Receiver::Pop(_, receiver) => receiver.is_empty(),
+            Receiver::Channels(receiver) => receiver.is_empty(),
+        }
+    }
+
+    // 取り出したまま処理されていないメッセージ
+    fn take_popped(&mut self) -> Option<(Pop, SubscribeMessage)> {
+        match self {
+            Receiver::Pop(pop, receiver) => receiver.try_recv().ok().map(|it| (*pop, it)),
+            Receiver::Channels(_) => None,
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// BLPOP/BRPOPを繰り返すタスクを動かす
+fn spawn_pop(
+    redis_pool: &deadpool_redis::Pool,
+    pop: Pop,
+    keys: Vec<String>,
+    block_timeout: Duration,
+    reconnect_interval: Duration,
+    token: &CancellationToken,
+    wake: &Arc<Notify>,
+) -> (Receiver, JoinHandle<()>) {
+    // 処理を待っている取り出し済みのメッセージは1つまで
+    let (sender, receiver) = mpsc::channel(1);
+    let feeder = spawn(pop_messages(
+        redis_pool.clone(),
+        pop,
+        keys,
+        block_timeout,
+        reconnect_interval,
+        token.clone(),
+        sender,
+        wake.clone(),
+    ));
+    (Receiver::Pop(pop, receiver), feeder)
+}
+
+// 取り出したメッセージを送り、ワーカーを起こす
+#[allow(clippy::too_many_arguments)]
+async fn pop_messages(
+    redis_pool: deadpool_redis::Pool,
+    pop: Pop,
+    keys: Vec<String>,
+    block_timeout: Duration,
+    reconnect_interval: Duration,
+    token: CancellationToken,
+    sender: mpsc::Sender<SubscribeMessage>,
+    wake: Arc<Notify>,
+) {
+    while !token.is_cancelled() {
+        let permit = tokio::select! {
+            permit = sender.reserve() => match permit {
+                Ok(permit) => permit,
+                Err(_) => break,
+            },
+            _ = token.cancelled() => break,
+        };
+        // 取り出したメッセージを失わないよう、キャンセルされても実行中のコマンドは待つ
+        match pop_once(&redis_pool, pop, &keys, block_timeout).await {
+            Ok(Some(message)) => {
+                debug!(key = message.key, "message popped");
+                permit.send(message);
+                wake.notify_one();
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!(error = %err, command = pop.command(), "redis pop error");
+                tokio::select! {
+                    _ = sleep(reconnect_interval) => {}
+                    _ = token.cancelled() => {}
+                }
+            }
+        }
+    }
+}
+
+// BLPOP/BRPOPを一度実行する、タイムアウトではOk(None)
+async fn pop_once(
+    redis_pool: &deadpool_redis::Pool,
+    pop: Pop,
+    keys: &[String],
+    block_timeout: Duration,
+) -> Result<Option<SubscribeMessage>, Error> {
+    let mut redis_connection = redis_pool.get().await?;
+    let res = redis::cmd(pop.command())
+        .arg(keys)
+        .arg(block_timeout.as_secs_f64())
+        .query_async::<Option<(String, String)>>(&mut *redis_connection)
+        .await?;
+    Ok(res.map(|(key, payload)| SubscribeMessage { key, payload }))
+}
+
+// 処理しないまま止まる場合は、取り出した側に戻す
+async fn push_back(redis_pool: &deadpool_redis::Pool, pop: Pop, message: SubscribeMessage) {
+    let res = match redis_pool.get().await {
+        Ok(mut redis_connection) => redis::cmd(pop.push_command())
+            .arg(&message.key)
+            .arg(&message.payload)
+            .query_async::<()>(&mut *redis_connection)
+            .await
+            .map_err(Error::from),
+        Err(err) => Err(err.into()),
+    };
+    match res {
+        Ok(()) => debug!(key = message.key, "message pushed back"),
+        Err(err) => {
+            warn!(error = %err, key = message.key, payload = message.payload, "message lost")
+        }
+    }
+}
+
+// 専用の接続でSUBSCRIBEし、受け取ったメッセージを送る
+async fn subscribe(
+    client: redis::Client,
+    channels: Vec<String>,
+    reconnect_interval: Duration,
+    token: CancellationToken,
+    sender: mpsc::UnboundedSender<SubscribeMessage>,
+    wake: Arc<Notify>,
+) {
+    while !token.is_cancelled() {
+        tokio::select! {
+            res = subscribe_once(&client, &channels, &sender, &wake) => {
+                if let Err(err) = res {
+                    warn!(error = %err, "redis subscribe error");
+                }
+            }
+            _ = token.cancelled() => break,
+        }
+        tokio::select! {
+            _ = sleep(reconnect_interval) => {}
+            _ = token.cancelled() => {}
+        }
+    }
+}
+
+// 接続が切れるまでメッセージを受け取る
+async fn subscribe_once(
+    client: &redis::Client,
+    channels: &[String],
+    sender: &mpsc::UnboundedSender<SubscribeMessage>,
+    wake: &Notify,
+) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channels).await?;
+    debug!(channels = ?channels, "subscribe started");
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        let payload = match message.get_payload::<String>() {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(error = %err, channel = message.get_channel_name(), "invalid payload");
+                continue;
+            }
+        };
+        let _ = sender.send(SubscribeMessage {
+            key: message.get_channel_name().to_owned(),
+            payload,
+        });
+        wake.notify_one();
+    }
+    warn!(channels = ?channels, "subscribe connection closed");
+    Ok(())
+}
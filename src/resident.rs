@@ -0,0 +1,459 @@
+use std::{
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{spawn, sync::watch, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    group::TaskGroup,
+    shutdown::{Shutdown, ShutdownResult},
+    signal::spawn_signal_handler,
+    status::{capture_status, lock, ExitReason, TaskStatus},
+};
+
+type TaskFactory = Box<dyn FnOnce(CancellationToken) -> JoinHandle<()> + Send>;
+
+///
+/// Resident
+///   owns the named tasks of the application, installs the signal handler,
+///   runs every task and waits until all of them finish
+///
+///   grace_period: time given to the tasks of each group to stop after the group is cancelled
+///
+///   When the token is cancelled the groups stop one by one in the reverse order
+///   of registration, each waiting for its tasks before the next one is cancelled.
+///   LoopState::AllTerminate stops only the group of the task,
+///   and the whole resident for the tasks added by task.
+///
+pub struct Resident {
+    token: CancellationToken,
+    reload_sender: watch::Sender<u64>,
+    grace_period: Duration,
+    tasks: Vec<(String, String, TaskFactory)>,
+}
+
+impl Default for Resident {
+    fn default() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            reload_sender: watch::channel(0).0,
+            grace_period: Duration::from_secs(10),
+            tasks: vec![],
+        }
+    }
+}
+
+impl Resident {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    ///
+    /// Registers a task, spawn_function receives the token and makes the task
+    /// e.g. `.task("batch", |token| make_looper(token, ...))`
+    ///
+    pub fn task<F>(self, name: impl Into<String>, spawn_function: F) -> Self
+    where
+        F: FnOnce(CancellationToken) -> JoinHandle<()> + Send + 'static,
+    {
+        self.task_in("", name, spawn_function)
+    }
+
+    ///
+    /// Registers a task in the group, spawn_function receives the token of the group
+    /// e.g. `.task_in("redis", "subscriber", |token| make_subscribe_worker(...))`
+    ///
+    pub fn task_in<F>(
+        mut self,
+        group: impl Into<String>,
+        name: impl Into<String>,
+        spawn_function: F,
+    ) -> Self
+    where
+        F: FnOnce(CancellationToken) -> JoinHandle<()> + Send + 'static,
+    {
+        self.tasks
+            .push((group.into(), name.into(), Box::new(spawn_function)));
+        self
+    }
+
+    ///
+    /// Token cancelled by SIGINT and SIGTERM
+    ///
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    ///
+    /// Receiver incremented by SIGHUP
+    ///
+    pub fn reload(&self) -> watch::Receiver<u64> {
+        self.reload_sender.subscribe()
+    }
+
+    pub async fn run(self) -> ResidentReport {
+        let signal = spawn_signal_handler(self.token.clone(), self.reload_sender);
+
+        // グループは最初に登録された順に並べる
+        let groups_token = CancellationToken::new();
+        let mut groups: Vec<(TaskGroup, Shutdown)> = vec![];
+        let mut statuses = vec![];
+        for (group_name, name, spawn_function) in self.tasks {
+            let index = match groups.iter().position(|(it, _)| it.name() == group_name) {
+                Some(index) => index,
+                None => {
+                    let group = TaskGroup::new(group_name.clone(), &groups_token);
+                    let shutdown = Shutdown::new(group.token(), self.grace_period);
+                    groups.push((group, shutdown));
+                    groups.len() - 1
+                }
+            };
+            let (group, shutdown) = &mut groups[index];
+            // 終了後は登録が消えるので、spawnした時点で状態を掴んでおく
+            let token = group.token();
+            let (handle, status) = capture_status(move || spawn_function(token));
+            statuses.push((group_name, name.clone(), status));
+            shutdown.push(name, handle);
+        }
+
+        let mut stops = vec![];
+        let mut waits = vec![];
+        for (group, shutdown) in groups {
+            let done = CancellationToken::new();
+            let wait_done = done.clone();
+            waits.push(spawn(async move {
+                let result = shutdown.wait().await;
+                wait_done.cancel();
+                result
+            }));
+            stops.push((group, done));
+        }
+        let stopper = spawn(stop_groups(self.token.clone(), stops));
+
+        let mut result = ShutdownResult::default();
+        for wait in waits {
+            if let Ok(res) = wait.await {
+                result.finished.extend(res.finished);
+                result.panicked.extend(res.panicked);
+                result.aborted.extend(res.aborted);
+            }
+        }
+
+        // シグナルの待ち受けを終わらせる
+        self.token.cancel();
+        let _ = stopper.await;
+        let _ = signal.await;
+
+        let report = ResidentReport {
+            tasks: statuses
+                .into_iter()
+                .map(|(group, name, status)| TaskReport::new(group, name, status, &mut result))
+                .collect(),
+        };
+        for task in &report.tasks {
+            info!(
+                group = task.group,
+                task = task.name,
+                exit = ?task.exit,
+                runs = task.runs,
+                panics = task.panics,
+                "task report"
+            );
+        }
+        report
+    }
+}
+
+// トークンがキャンセルされたら、登録と逆順にグループを1つずつ止める
+// 既定のグループが止まった場合は全体を止める
+async fn stop_groups(token: CancellationToken, groups: Vec<(TaskGroup, CancellationToken)>) {
+    let default_token = groups
+        .iter()
+        .find(|(group, _)| group.name().is_empty())
+        .map(|(group, _)| group.token());
+    tokio::select! {
+        _ = token.cancelled() => {}
+        _ = async {
+            match &default_token {
+                Some(default_token) => default_token.cancelled().await,
+                None => std::future::pending().await,
+            }
+        } => token.cancel(),
+    }
+    for (group, done) in groups.iter().rev() {
+        if !done.is_cancelled() {
+            group.cancel();
+        }
+        done.cancelled().await;
+    }
+}
+
+///
+/// TaskExit
+///   Finished: the task finished with the reason of the loop
+///     None when the task was not made by make_looper or make_worker
+///   Panicked: the task itself panicked, e.g. in stop_function
+///   Aborted: the task did not finish within the grace period
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskExit {
+    Finished(Option<ExitReason>),
+    Panicked,
+    Aborted,
+}
+
+///
+/// TaskReport
+///   group: group given to Resident::task_in, empty for Resident::task
+///   name: name given to Resident::task
+///   exit: how the task finished
+///   runs: number of started executions
+///   panics: number of panicked executions
+///
+#[derive(Debug, Clone)]
+pub struct TaskReport {
+    pub group: String,
+    pub name: String,
+    pub exit: TaskExit,
+    pub runs: u64,
+    pub panics: u64,
+}
+
+impl TaskReport {
+    fn new(
+        group: String,
+        name: String,
+        status: Option<Arc<Mutex<TaskStatus>>>,
+        result: &mut ShutdownResult,
+    ) -> Self {
+        let status = status.map(|it| lock(&it).clone());
+        let exit = if take(&mut result.aborted, &name) {
+            TaskExit::Aborted
+        } else if take(&mut result.panicked, &name) {
+            TaskExit::Panicked
+        } else {
+            take(&mut result.finished, &name);
+            TaskExit::Finished(status.as_ref().and_then(|it| it.exit))
+        };
+        Self {
+            group,
+            name,
+            exit,
+            runs: status.as_ref().map_or(0, |it| it.runs),
+            panics: status.as_ref().map_or(0, |it| it.panics),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        !matches!(
+            self.exit,
+            TaskExit::Panicked | TaskExit::Aborted | TaskExit::Finished(Some(ExitReason::Panicked))
+        )
+    }
+}
+
+// 同じ名前のタスクがあっても1つずつ対応させる
+fn take(names: &mut Vec<String>, name: &str) -> bool {
+    match names.iter().position(|it| it == name) {
+        Some(index) => {
+            names.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+///
+/// ResidentReport
+///   tasks: report of each task in the order they were registered
+///
+#[derive(Debug, Clone, Default)]
+pub struct ResidentReport {
+    pub tasks: Vec<TaskReport>,
+}
+
+impl ResidentReport {
+    ///
+    /// No task panicked or was aborted
+    ///
+    pub fn is_success(&self) -> bool {
+        self.tasks.iter().all(|it| it.is_success())
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        if self.is_success() {
+            ExitCode::SUCCESS
+        } else {
+            for task in self.tasks.iter().filter(|it| !it.is_success()) {
+                warn!(task = task.name, exit = ?task.exit, "task failed");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::{make_worker, make_worker_with_config, signal::SIGNAL_TEST, LoopConfig, LoopState};
+
+    #[tokio::test]
+    async fn test_resident() -> anyhow::Result<()> {
+        let _serial = SIGNAL_TEST.lock().await;
+        let resident = Resident::new()
+            .grace_period(Duration::from_millis(100))
+            .task("terminate", |token| {
+                make_worker(
+                    token,
+                    Duration::from_millis(10),
+                    |_| async { LoopState::Terminate },
+                    || async {},
+                )
+            })
+            .task("panic", |token| {
+                make_worker_with_config(
+                    (),
+                    token,
+                    Duration::from_millis(10),
+                    LoopConfig::default(),
+                    |_, _, _| async { panic!("boom") },
+                    |_| async {},
+                )
+            })
+            .task("hang", |token| {
+                make_worker(
+                    token,
+                    Duration::from_millis(10),
+                    |_| async {
+                        sleep(Duration::from_secs(60)).await;
+                        LoopState::Continue
+                    },
+                    || async {},
+                )
+            });
+        let token = resident.token();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            token.cancel();
+        });
+
+        let report = resident.run().await;
+        let exits: Vec<_> = report
+            .tasks
+            .iter()
+            .map(|it| (it.name.as_str(), it.exit, it.runs, it.panics))
+            .collect();
+        assert_eq!(
+            exits,
+            vec![
+                (
+                    "terminate",
+                    TaskExit::Finished(Some(ExitReason::Terminated)),
+                    1,
+                    0
+                ),
+                (
+                    "panic",
+                    TaskExit::Finished(Some(ExitReason::Panicked)),
+                    1,
+                    1
+                ),
+                ("hang", TaskExit::Aborted, 1, 0),
+            ]
+        );
+        assert!(!report.is_success());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_resident_finished_early() -> anyhow::Result<()> {
+        let _serial = SIGNAL_TEST.lock().await;
+        // spawn直後に終わったタスクも終了理由を失わない
+        for _ in 0..100 {
+            let report = Resident::new()
+                .task("terminate", |token| {
+                    make_worker(token, None, |_| async { LoopState::Terminate }, || async {})
+                })
+                .run()
+                .await;
+            let task = &report.tasks[0];
+            assert_eq!(task.exit, TaskExit::Finished(Some(ExitReason::Terminated)));
+            assert_eq!(task.runs, 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resident_groups() -> anyhow::Result<()> {
+        let _serial = SIGNAL_TEST.lock().await;
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let worker_stopped = stopped.clone();
+        let worker = move |token, name: &'static str, delay: u64| {
+            let stopped = worker_stopped.clone();
+            make_worker(
+                token,
+                None,
+                |_| async { LoopState::Duration(Duration::from_secs(60)) },
+                move || {
+                    let stopped = stopped.clone();
+                    async move {
+                        sleep(Duration::from_millis(delay)).await;
+                        stopped.lock().unwrap().push(name);
+                    }
+                },
+            )
+        };
+        let first = worker.clone();
+        let second = worker.clone();
+        let resident = Resident::new()
+            .task_in("first", "first", move |token| first(token, "first", 0))
+            .task_in("second", "second", move |token| second(token, "second", 30))
+            .task_in("terminate", "terminate", |token| {
+                make_worker(
+                    token,
+                    None,
+                    |_| async { LoopState::AllTerminate },
+                    || async {},
+                )
+            });
+        let token = resident.token();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            token.cancel();
+        });
+
+        // 他のグループのAllTerminateでは止まらず、
+        // 後から登録したグループが止まってから次のグループを止める
+        let report = resident.run().await;
+        assert_eq!(*stopped.lock().unwrap(), vec!["second", "first"]);
+        let groups: Vec<_> = report
+            .tasks
+            .iter()
+            .map(|it| (it.group.as_str(), it.exit))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                ("first", TaskExit::Finished(Some(ExitReason::Cancelled))),
+                ("second", TaskExit::Finished(Some(ExitReason::Cancelled))),
+                (
+                    "terminate",
+                    TaskExit::Finished(Some(ExitReason::AllTerminated))
+                ),
+            ]
+        );
+        assert!(report.is_success());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::future::{ready, Future};
+
+///
+/// Resource
+///   acquired before each execution and passed to task_function and stop_function
+///   implemented for (), the pools of each feature and tuples of resources
+///   is_err: whether acquiring failed, counted as pool errors in the metrics
+///   ping: whether the resource can be acquired now, used by the readiness check
+///
+pub trait Resource: Send + Sync + 'static {
+    type Output: Send + 'static;
+
+    fn acquire(&self) -> impl Future<Output = Self::Output> + Send;
+
+    fn is_err(_output: &Self::Output) -> bool {
+        false
+    }
+
+    fn ping(&self) -> impl Future<Output = bool> + Send {
+        async move { !Self::is_err(&self.acquire().await) }
+    }
+}
+
+impl Resource for () {
+    type Output = ();
+
+    fn acquire(&self) -> impl Future<Output = Self::Output> + Send {
+        ready(())
+    }
+}
+
+macro_rules! impl_resource_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Resource),+> Resource for ($($name,)+) {
+            type Output = ($($name::Output,)+);
+
+            #[allow(non_snake_case)]
+            fn acquire(&self) -> impl Future<Output = Self::Output> + Send {
+                let ($($name,)+) = self;
+                async move { ($($name.acquire().await,)+) }
+            }
+
+            #[allow(non_snake_case)]
+            fn is_err(output: &Self::Output) -> bool {
+                let ($($name,)+) = output;
+                false $(|| $name::is_err($name))+
+            }
+
+            #[allow(non_snake_case)]
+            fn ping(&self) -> impl Future<Output = bool> + Send {
+                let ($($name,)+) = self;
+                async move { true $(&& $name.ping().await)+ }
+            }
+        }
+    };
+}
+
+impl_resource_tuple!(A);
+impl_resource_tuple!(A, B);
+impl_resource_tuple!(A, B, C);
+impl_resource_tuple!(A, B, C, D);
@@ -0,0 +1,684 @@
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, sync::Arc, time::Duration};
+
+use chrono::prelude::*;
+use cron::Schedule;
+use futures_util::FutureExt;
+use tokio::{
+    spawn,
+    sync::Notify,
+    task::{JoinError, JoinHandle, JoinSet},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info_span, warn, Instrument, Span};
+
+use crate::{
+    clock::Clock,
+    control::Control,
+    execute_sleep,
+    lock::TaskLockGuard,
+    next_tick,
+    resource::Resource,
+    status::{ExitReason, TaskKind, TaskRecorder},
+    LoopConfig, LoopState, OverlapPolicy, TaskContext,
+};
+
+pub(crate) type Panic = Box<dyn Any + Send>;
+
+// 各make_looperの共通処理
+pub(crate) fn spawn_looper<R, Task, Fut1, Stop, Fut2>(
+    token: CancellationToken,
+    mut schedule: Schedule,
+    stop_check_duration: Option<Duration>,
+    config: LoopConfig,
+    resource: R,
+    task_function: Task,
+    stop_function: Stop,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    Task: Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    Fut1: Future<Output = LoopState> + Send + 'static,
+    Stop: Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+    Fut2: Future<Output = ()> + Send,
+{
+    let recorder = TaskRecorder::register(TaskKind::Looper, &config.name, config.clock.now());
+    let task_span = info_span!("task", name = %recorder.name());
+    // 名前が無いとプロセスごとの名前でロックするため、他のレプリカと排他にならない
+    if config.lock.is_some() && config.name.is_empty() {
+        warn!(name = %recorder.name(), "lock is keyed by the name local to the process, set LoopConfig name");
+    }
+    let task_recorder = recorder.clone();
+    let handle = spawn(async move {
+        let recorder = task_recorder;
+        let name = recorder.name();
+        let mut next_tick: DateTime<Utc> =
+            match next_tick(&schedule, &config.timezone, &config.clock.now()) {
+                Some(next_tick) => next_tick,
+                None => {
+                    recorder.stop(ExitReason::ScheduleEnded);
+                    stop_function(resource.acquire().await).await;
+                    return;
+                }
+            };
+        let task_function = Arc::new(task_function);
+        let resource = Arc::new(resource);
+        let mut running: JoinSet<Execution> = JoinSet::new();
+        let mut run_token = token.child_token();
+        let mut streak = Streak::default();
+        // レート制限で実行を遅らせている場合に、使えるようになる時間
+        let mut rate_limited = None;
+        let reason = loop {
+            // グレースフルストップのチェック
+            if token.is_cancelled() {
+                break ExitReason::Cancelled;
+            }
+
+            // 並行実行で終わった処理の結果を反映する
+            if let Some(reason) = reap(
+                &mut running,
+                &token,
+                &config,
+                &recorder,
+                &mut schedule,
+                &mut streak,
+                &mut next_tick,
+            ) {
+                break reason;
+            }
+
+            // 一時停止中は再開か手動実行まで待つ、並行実行の終了も反映する
+            if recorder.control().is_paused() {
+                match wait_paused(recorder.control(), &token, join_running(&mut running)).await {
+                    Paused::Triggered => next_tick = config.clock.now(),
+                    Paused::Resumed => {
+                        // 一時停止中に過ぎた時刻はMisfirePolicyに従う
+                        let Some(tick) = config.misfire.missed(
+                            &schedule,
+                            &config.timezone,
+                            &next_tick,
+                            &config.clock.now(),
+                        ) else {
+                            break ExitReason::ScheduleEnded;
+                        };
+                        next_tick = tick;
+                        continue;
+                    }
+                    Paused::Other(res) => {
+                        if let Some(reason) = reflect(
+                            res,
+                            &token,
+                            &config,
+                            &recorder,
+                            &mut schedule,
+                            &mut streak,
+                            &mut next_tick,
+                        ) {
+                            break reason;
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                }
+            }
+
+            let now = config.clock.now();
+            // 停止していたなどで遅れて起きた場合は、逃した時刻をMisfirePolicyに従って扱う
+            if now >= next_tick {
+                let Some(tick) =
+                    config
+                        .misfire
+                        .catch_up(&schedule, &config.timezone, &next_tick, &now)
+                else {
+                    break ExitReason::ScheduleEnded;
+                };
+                next_tick = tick;
+            }
+            if now >= start_at(&next_tick, &rate_limited) {
+                // 並行実行の扱いを先に決め、実行中の処理が持っているロックは先に手放させる
+                if config.overlap == OverlapPolicy::CancelPrevious && !running.is_empty() {
+                    debug!(scheduled = %next_tick, "cancel previous execution");
+                    run_token.cancel();
+                    running.abort_all();
+                    run_token = token.child_token();
+                    let mut reason = None;
+                    while let Some(res) = running.join_next().await {
+                        reason = reflect(
+                            res,
+                            &token,
+                            &config,
+                            &recorder,
+                            &mut schedule,
+                            &mut streak,
+                            &mut next_tick,
+                        )
+                        .or(reason);
+                    }
+                    if let Some(reason) = reason {
+                        break reason;
+                    }
+                    continue;
+                }
+                if matches!(config.overlap, OverlapPolicy::Allow(max) if running.len() >= max.max(1))
+                {
+                    warn!(scheduled = %next_tick, running = running.len(), "skip overlapped tick");
+                    let Some(tick) = config.misfire.next_tick(
+                        &schedule,
+                        &config.timezone,
+                        &next_tick,
+                        &config.clock.now(),
+                    ) else {
+                        break ExitReason::ScheduleEnded;
+                    };
+                    next_tick = tick;
+                    continue;
+                }
+                let Some(held) = take_lock(&config, &name).await else {
+                    // 他で実行しているので、実行せずに次の時刻にする
+                    let Some(tick) = config.misfire.next_tick(
+                        &schedule,
+                        &config.timezone,
+                        &next_tick,
+                        &config.clock.now(),
+                    ) else {
+                        break ExitReason::ScheduleEnded;
+                    };
+                    next_tick = tick;
+                    continue;
+                };
+                let budget = match take_budget(&config, &now) {
+                    Ok(budget) => budget,
+                    Err(available) => {
+                        // 上限に達していれば使えるようになるまで遅らせる、遅れた間の時刻はMisfirePolicyに従う
+                        debug!(%available, "rate limited");
+                        held.unlock().await;
+                        rate_limited = Some(available);
+                        continue;
+                    }
+                };
+                // 定期的に行う処理実行
+                let context = TaskContext {
+                    scheduled: next_tick,
+                    started: now,
+                    budget,
+                };
+                match config.overlap {
+                    OverlapPolicy::Forbid => {
+                        let span = execution_span(recorder.start(now), &context);
+                        let run_token = token.child_token();
+                        let res = catch_panic(with_timeout(
+                            async {
+                                let resource = acquire(&*resource, &recorder).await;
+                                task_function(context.clone(), resource, run_token.clone()).await
+                            },
+                            &run_token,
+                            config.timeout,
+                            &config.on_timeout,
+                        ))
+                        .instrument(span.clone())
+                        .await;
+                        held.unlock().await;
+                        record_state(&span, &res);
+                        recorder.finish(&res, context.started, config.clock.now());
+                        let Some((state, backoff)) = restart(res, &config, &mut streak.panics)
+                        else {
+                            break ExitReason::Panicked;
+                        };
+                        let Some(res) = state.looper(
+                            &token,
+                            &context,
+                            &mut schedule,
+                            &mut streak.failures,
+                            &config,
+                        ) else {
+                            break exit_reason(&state);
+                        };
+                        next_tick = restart_tick(res, backoff, &*config.clock);
+                    }
+                    _ => {
+                        let span = execution_span(recorder.start(now), &context);
+                        let task_function = task_function.clone();
+                        let resource = resource.clone();
+                        let task_recorder = recorder.clone();
+                        let run_token = run_token.child_token();
+                        let task_context = context.clone();
+                        let timeout = config.timeout;
+                        let on_timeout = config.on_timeout.clone();
+                        // プールの取得待ちでスケジューラを止めないよう、取得も実行側で行う
+                        let future = catch_panic(async move {
+                            with_timeout(
+                                async {
+                                    let resource = acquire(&*resource, &task_recorder).await;
+                                    task_function(task_context, resource, run_token.clone())
+                                        .await
+                                },
+                                &run_token,
+                                timeout,
+                                &on_timeout,
+                            )
+                            .await
+                        });
+                        running.spawn(async move {
+                            let res = future.instrument(span.clone()).await;
+                            held.unlock().await;
+                            record_state(&span, &res);
+                            (context, res)
+                        });
+                        let Some(res) = config.misfire.next_tick(
+                            &schedule,
+                            &config.timezone,
+                            &next_tick,
+                            &config.clock.now(),
+                        ) else {
+                            break ExitReason::ScheduleEnded;
+                        };
+                        next_tick = res;
+                    }
+                }
+            }
+
+            let wake_at = start_at(&next_tick, &rate_limited);
+            recorder.next_tick(wake_at);
+            // 並行実行が終わった場合もすぐに結果を反映する
+            tokio::select! {
+                _ = execute_sleep(&token, &stop_check_duration, &wake_at, &*config.clock) => {}
+                res = join_running(&mut running) => {
+                    if let Some(reason) = reflect(
+                        res,
+                        &token,
+                        &config,
+                        &recorder,
+                        &mut schedule,
+                        &mut streak,
+                        &mut next_tick,
+                    ) {
+                        break reason;
+                    }
+                }
+                _ = recorder.control().triggered() => {
+                    debug!("looper triggered");
+                    next_tick = config.clock.now();
+                }
+            }
+        };
+
+        // 実行中の処理の終了を待つ
+        while running.join_next().await.is_some() {}
+        recorder.stop(reason);
+        stop_function(resource.acquire().await).await;
+    }.instrument(task_span));
+    recorder.attach(handle.id());
+    handle
+}
+
+// 各make_workerの共通処理
+pub(crate) fn spawn_worker<R, Task, Fut1, Stop, Fut2>(
+    token: CancellationToken,
+    stop_check_duration: Option<Duration>,
+    config: LoopConfig,
+    resource: R,
+    task_function: Task,
+    stop_function: Stop,
+) -> JoinHandle<()>
+where
+    R: Resource,
+    Task: Fn(TaskContext, R::Output, CancellationToken) -> Fut1 + Send + Sync + 'static,
+    Fut1: Future<Output = LoopState> + Send,
+    Stop: Fn(R::Output) -> Fut2 + Send + Sync + 'static,
+    Fut2: Future<Output = ()> + Send,
+{
+    let recorder = TaskRecorder::register(TaskKind::Worker, &config.name, config.clock.now());
+    let task_span = info_span!("task", name = %recorder.name());
+    let task_recorder = recorder.clone();
+    let handle = spawn(
+        async move {
+            let recorder = task_recorder;
+            // 動き出した瞬間は実行する
+            let mut next_tick: DateTime<Utc> = config.clock.now();
+            let mut streak = Streak::default();
+            // レート制限で実行を遅らせている場合に、使えるようになる時間
+            let mut rate_limited = None;
+            let reason = loop {
+                // グレースフルストップのチェック
+                if token.is_cancelled() {
+                    break ExitReason::Cancelled;
+                }
+
+                // 一時停止中は再開か手動実行まで待つ
+                if recorder.control().is_paused() {
+                    match wait_paused(recorder.control(), &token, std::future::pending::<()>())
+                        .await
+                    {
+                        Paused::Triggered => next_tick = config.clock.now(),
+                        _ => continue,
+                    }
+                }
+
+                // 現在時間と次実行する処理の時間をチェックする
+                let now = config.clock.now();
+                if now >= start_at(&next_tick, &rate_limited) {
+                    let budget = match take_budget(&config, &now) {
+                        Ok(budget) => budget,
+                        Err(available) => {
+                            // 上限に達していれば使えるようになるまで遅らせる
+                            debug!(%available, "rate limited");
+                            rate_limited = Some(available);
+                            continue;
+                        }
+                    };
+                    // 定期的に行う処理実行
+                    let context = TaskContext {
+                        scheduled: next_tick,
+                        started: now,
+                        budget,
+                    };
+                    let span = execution_span(recorder.start(now), &context);
+                    let run_token = token.child_token();
+                    let res = catch_panic(with_timeout(
+                        async {
+                            let resource = acquire(&resource, &recorder).await;
+                            task_function(context, resource, run_token.clone()).await
+                        },
+                        &run_token,
+                        config.timeout,
+                        &config.on_timeout,
+                    ))
+                    .instrument(span.clone())
+                    .await;
+                    record_state(&span, &res);
+                    recorder.finish(&res, now, config.clock.now());
+                    let Some((state, backoff)) = restart(res, &config, &mut streak.panics) else {
+                        break ExitReason::Panicked;
+                    };
+                    let Some(res) = state.worker(&token, &now, &mut streak.failures, &config)
+                    else {
+                        break exit_reason(&state);
+                    };
+                    next_tick = restart_tick(res, backoff, &*config.clock);
+                }
+
+                let wake_at = start_at(&next_tick, &rate_limited);
+                recorder.next_tick(wake_at);
+                tokio::select! {
+                    _ = execute_sleep(&token, &stop_check_duration, &wake_at, &*config.clock) => {}
+                    _ = wait_wake(&config.wake) => {
+                        // 起こされたらすぐに実行する
+                        debug!("worker woken");
+                        next_tick = config.clock.now();
+                    }
+                    _ = recorder.control().triggered() => {
+                        debug!("worker triggered");
+                        next_tick = config.clock.now();
+                    }
+                }
+            };
+            recorder.stop(reason);
+            stop_function(resource.acquire().await).await;
+        }
+        .instrument(task_span),
+    );
+    recorder.attach(handle.id());
+    handle
+}
+
+// 起こされるまで待つ、指定が無ければ起こされない
+async fn wait_wake(wake: &Option<Arc<Notify>>) {
+    match wake {
+        Some(wake) => wake.notified().await,
+        None => std::future::pending().await,
+    }
+}
+
+// 一時停止中に待つのをやめた理由
+enum Paused<T> {
+    Triggered,
+    Resumed,
+    Cancelled,
+    Other(T),
+}
+
+// 一時停止が終わるか、手動実行、キャンセル、otherの完了まで待つ
+// Triggered以外はループの最初からやり直す
+async fn wait_paused<T>(
+    control: &Control,
+    token: &CancellationToken,
+    other: impl Future<Output = T>,
+) -> Paused<T> {
+    tokio::select! {
+        _ = control.triggered() => Paused::Triggered,
+        _ = control.resumed() => Paused::Resumed,
+        _ = token.cancelled() => Paused::Cancelled,
+        res = other => Paused::Other(res),
+    }
+}
+
+// 実行中に持っているLoopConfigのlock
+struct Held(Option<Box<dyn TaskLockGuard>>);
+
+impl Held {
+    async fn unlock(self) {
+        if let Some(guard) = self.0 {
+            guard.unlock().await;
+        }
+    }
+}
+
+// LoopConfigのlockを取る、取れなければNone
+async fn take_lock(config: &LoopConfig, name: &str) -> Option<Held> {
+    let Some(lock) = &config.lock else {
+        return Some(Held(None));
+    };
+    let guard = lock.try_lock(name).await?;
+    Some(Held(Some(guard)))
+}
+
+// 実行を始める時にレート制限の残りを1つ使う、使い切っていれば使える時間を返す
+fn take_budget(config: &LoopConfig, now: &DateTime<Utc>) -> Result<Option<u32>, DateTime<Utc>> {
+    match &config.rate_limit {
+        Some(rate_limit) => rate_limit.take(*now).map(Some),
+        None => Ok(None),
+    }
+}
+
+// 実行を始める時間、レート制限で遅らせている場合は予定の時刻より後になる
+fn start_at(next_tick: &DateTime<Utc>, rate_limited: &Option<DateTime<Utc>>) -> DateTime<Utc> {
+    rate_limited.map_or(*next_tick, |it| it.max(*next_tick))
+}
+
+// LoopStateで終了した場合の終了理由
+fn exit_reason(state: &LoopState) -> ExitReason {
+    match state {
+        LoopState::AllTerminate => ExitReason::AllTerminated,
+        LoopState::Terminate => ExitReason::Terminated,
+        _ => ExitReason::ScheduleEnded,
+    }
+}
+
+// 1回の実行のspan、結果のLoopStateは終了後に記録する
+fn execution_span(run: u64, context: &TaskContext) -> Span {
+    info_span!(
+        "execution",
+        run,
+        scheduled = %context.scheduled,
+        state = field::Empty
+    )
+}
+
+fn record_state(span: &Span, res: &Result<LoopState, Panic>) {
+    match res {
+        Ok(state) => span.record("state", field::debug(state)),
+        Err(_) => span.record("state", "panic"),
+    };
+}
+
+// 処理に渡すリソースを取得する、失敗は記録する
+async fn acquire<R: Resource>(resource: &R, recorder: &TaskRecorder) -> R::Output {
+    let output = resource.acquire().await;
+    if R::is_err(&output) {
+        recorder.pool_error();
+    }
+    output
+}
+
+// 実行時間の上限を超えたら打ち切り、処理に渡したトークンもキャンセルする
+async fn with_timeout<Fut>(
+    future: Fut,
+    token: &CancellationToken,
+    duration: Option<Duration>,
+    on_timeout: &LoopState,
+) -> LoopState
+where
+    Fut: Future<Output = LoopState>,
+{
+    let Some(duration) = duration else {
+        return future.await;
+    };
+    match timeout(duration, future).await {
+        Ok(state) => state,
+        Err(_) => {
+            token.cancel();
+            warn!(timeout = ?duration, state = ?on_timeout, "execution timed out");
+            on_timeout.clone()
+        }
+    }
+}
+
+// パニックを捕捉する
+async fn catch_panic<Fut>(future: Fut) -> Result<LoopState, Panic>
+where
+    Fut: Future<Output = LoopState>,
+{
+    AssertUnwindSafe(future).catch_unwind().await
+}
+
+// パニックした場合はRestartPolicyに従ってContinueと再開までの時間に置き換える
+// 再開しない場合はNone
+fn restart(
+    res: Result<LoopState, Panic>,
+    config: &LoopConfig,
+    panics: &mut u64,
+) -> Option<(LoopState, Duration)> {
+    match res {
+        Ok(state) => {
+            *panics = 0;
+            Some((state, Duration::ZERO))
+        }
+        Err(panic) => {
+            *panics += 1;
+            let backoff = config.restart.backoff(*panics);
+            error!(
+                panic = panic_message(&panic),
+                panics = *panics,
+                restart = backoff.is_some(),
+                "task panicked"
+            );
+            backoff.map(|backoff| (LoopState::Continue, backoff))
+        }
+    }
+}
+
+// パニック後の再開はbackoffより早くしない、パニックでなければそのまま
+fn restart_tick(tick: DateTime<Utc>, backoff: Duration, clock: &dyn Clock) -> DateTime<Utc> {
+    if backoff > Duration::ZERO {
+        tick.max(clock.now() + backoff)
+    } else {
+        tick
+    }
+}
+
+fn panic_message(panic: &Panic) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+// 連続したパニックと失敗の回数
+#[derive(Default)]
+struct Streak {
+    panics: u64,
+    failures: u64,
+}
+
+// 並行実行した処理の結果
+type Execution = (TaskContext, Result<LoopState, Panic>);
+
+// 並行実行が終わるまで待つ、実行中でなければ待ち続ける
+async fn join_running(running: &mut JoinSet<Execution>) -> Result<Execution, JoinError> {
+    match running.join_next().await {
+        Some(res) => res,
+        None => std::future::pending().await,
+    }
+}
+
+// 終了済みの処理の結果をすべて反映する、ループを終了する場合は終了理由を返す
+fn reap(
+    running: &mut JoinSet<Execution>,
+    token: &CancellationToken,
+    config: &LoopConfig,
+    recorder: &TaskRecorder,
+    schedule: &mut Schedule,
+    streak: &mut Streak,
+    next_tick: &mut DateTime<Utc>,
+) -> Option<ExitReason> {
+    let mut result = None;
+    while let Some(res) = running.try_join_next() {
+        if let Some(reason) = reflect(res, token, config, recorder, schedule, streak, next_tick) {
+            result = Some(reason);
+        }
+    }
+    result
+}
+
+// 終了した処理の結果を反映する、ループを終了する場合は終了理由を返す
+fn reflect(
+    res: Result<Execution, JoinError>,
+    token: &CancellationToken,
+    config: &LoopConfig,
+    recorder: &TaskRecorder,
+    schedule: &mut Schedule,
+    streak: &mut Streak,
+    next_tick: &mut DateTime<Utc>,
+) -> Option<ExitReason> {
+    let (context, res) = match res {
+        Ok(res) => res,
+        Err(err) => {
+            debug!(error = ?err, "execution join error");
+            recorder.abort(config.clock.now());
+            return None;
+        }
+    };
+    recorder.finish(&res, context.started, config.clock.now());
+    let Some((state, backoff)) = restart(res, config, &mut streak.panics) else {
+        return Some(ExitReason::Panicked);
+    };
+    *next_tick = restart_tick(*next_tick, backoff, &*config.clock);
+    match state {
+        LoopState::AllTerminate => {
+            token.cancel();
+            Some(ExitReason::AllTerminated)
+        }
+        LoopState::Terminate => Some(ExitReason::Terminated),
+        LoopState::Continue => {
+            streak.failures = 0;
+            None
+        }
+        _ => {
+            let Some(tick) = state.looper(token, &context, schedule, &mut streak.failures, config)
+            else {
+                return Some(ExitReason::ScheduleEnded);
+            };
+            if matches!(state, LoopState::Reschedule(_)) {
+                // スケジュールを変えた場合は新しいスケジュールの時間にする
+                *next_tick = tick;
+            } else {
+                // 指定時間は次の実行を遅らせる
+                *next_tick = (*next_tick).max(tick);
+            }
+            None
+        }
+    }
+}
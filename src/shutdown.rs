@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use futures_util::future::select_all;
+use tokio::{task::JoinHandle, time::timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+#[derive(Debug, Default)]
+pub struct ShutdownResult {
+    pub finished: Vec<String>,
+    pub panicked: Vec<String>,
+    pub aborted: Vec<String>,
+}
+
+///
+/// Shutdown
+///   waits until the token is cancelled, then gives the tasks grace_period
+///   to finish and run stop_function, and aborts the tasks still running
+///
+pub struct Shutdown {
+    token: CancellationToken,
+    grace_period: Duration,
+    tasks: Vec<(String, JoinHandle<()>)>,
+}
+
+impl Shutdown {
+    pub fn new(token: CancellationToken, grace_period: Duration) -> Self {
+        Self {
+            token,
+            grace_period,
+            tasks: vec![],
+        }
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, handle: JoinHandle<()>) {
+        self.tasks.push((name.into(), handle));
+    }
+
+    pub async fn wait(mut self) -> ShutdownResult {
+        let mut result = ShutdownResult::default();
+        tokio::select! {
+            _ = self.token.cancelled() => {}
+            _ = join_all(&mut self.tasks, &mut result) => return result,
+        }
+
+        // 猶予期間内に終わらなければ強制終了
+        if timeout(self.grace_period, join_all(&mut self.tasks, &mut result))
+            .await
+            .is_err()
+        {
+            for (name, handle) in self.tasks.drain(..) {
+                warn!(task = name, "abort task");
+                handle.abort();
+                result.aborted.push(name);
+            }
+        }
+        result
+    }
+}
+
+// 全てのタスクの終了を待つ、途中でキャンセルされても終わったタスクは結果に反映済み
+async fn join_all(tasks: &mut Vec<(String, JoinHandle<()>)>, result: &mut ShutdownResult) {
+    while !tasks.is_empty() {
+        let (res, index, _) = select_all(tasks.iter_mut().map(|(_, handle)| handle)).await;
+        let (name, _) = tasks.remove(index);
+        match res {
+            Ok(()) => {
+                debug!(task = name, "task finished");
+                result.finished.push(name);
+            }
+            Err(err) => {
+                warn!(task = name, error = ?err, "task panicked");
+                result.panicked.push(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::{make_worker, LoopState};
+
+    #[tokio::test]
+    async fn test_shutdown() -> anyhow::Result<()> {
+        let token = CancellationToken::new();
+        let mut shutdown = Shutdown::new(token.clone(), Duration::from_millis(100));
+        shutdown.push(
+            "finish",
+            make_worker(
+                token.clone(),
+                Duration::from_millis(10),
+                |_| async { LoopState::Duration(Duration::from_secs(60)) },
+                || async {},
+            ),
+        );
+        shutdown.push(
+            "hang",
+            make_worker(
+                token.clone(),
+                Duration::from_millis(10),
+                |_| async {
+                    sleep(Duration::from_secs(60)).await;
+                    LoopState::Continue
+                },
+                || async {},
+            ),
+        );
+
+        sleep(Duration::from_millis(50)).await;
+        token.cancel();
+        let result = shutdown.wait().await;
+        assert_eq!(result.finished, vec!["finish".to_owned()]);
+        assert_eq!(result.aborted, vec!["hang".to_owned()]);
+        assert!(result.panicked.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use tokio::{spawn, sync::watch, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::status::task_statuses;
+
+///
+/// Handles the signals of the process
+///   SIGINT, SIGTERM: cancel the token
+///   SIGHUP: increment the value of the reload receiver
+///   SIGUSR1: log the status of every looper and worker
+///
+/// Only ctrl-c is handled on platforms other than unix.
+///
+pub fn signal_handler() -> (JoinHandle<()>, CancellationToken, watch::Receiver<u64>) {
+    let token = CancellationToken::new();
+    let (reload_sender, reload_receiver) = watch::channel(0);
+    (
+        spawn_signal_handler(token.clone(), reload_sender),
+        token,
+        reload_receiver,
+    )
+}
+
+// シグナルを待ち受けて、終了のシグナルでトークンをキャンセルする
+pub(crate) fn spawn_signal_handler(
+    token: CancellationToken,
+    reload_sender: watch::Sender<u64>,
+) -> JoinHandle<()> {
+    spawn(async move {
+        tokio::select! {
+            res = wait_signals(&reload_sender) => {
+                if let Err(err) = res {
+                    debug!(error = ?err, "signal error");
+                }
+                token.cancel();
+            }
+            _ = token.cancelled() => {}
+        }
+    })
+}
+
+///
+/// Logs the status of every looper and worker
+///
+pub fn dump_task_statuses() {
+    for status in task_statuses() {
+        info!(
+            id = status.id,
+            name = status.name,
+            kind = ?status.kind,
+            running = status.running,
+            next_tick = ?status.next_tick,
+            runs = status.runs,
+            panics = status.panics,
+            last_started = ?status.last_started,
+            last_finished = ?status.last_finished,
+            exit = ?status.exit,
+            paused = status.paused,
+            "task status"
+        );
+    }
+}
+
+// 終了のシグナルを受け取るまで待つ
+#[cfg(unix)]
+async fn wait_signals(reload_sender: &watch::Sender<u64>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user_defined1 = signal(SignalKind::user_defined1())?;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => {
+                debug!("received SIGINT");
+                return Ok(());
+            }
+            _ = terminate.recv() => {
+                debug!("received SIGTERM");
+                return Ok(());
+            }
+            _ = hangup.recv() => {
+                debug!("received SIGHUP");
+                reload_sender.send_modify(|it| *it += 1);
+            }
+            _ = user_defined1.recv() => {
+                debug!("received SIGUSR1");
+                dump_task_statuses();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signals(_reload_sender: &watch::Sender<u64>) -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    debug!("received ctrl-c");
+    Ok(())
+}
+
+// プロセスへのシグナルは他のテストのsignal handlerにも届くため、シグナルを扱うテストは順に実行する
+#[cfg(test)]
+pub(crate) static SIGNAL_TEST: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{process::Command, time::Duration};
+
+    use tokio::{
+        signal::unix::{signal, SignalKind},
+        time::timeout,
+    };
+
+    use super::*;
+
+    fn kill(signal: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{signal}"))
+            .arg(std::process::id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[tokio::test]
+    async fn test_signal_handler() -> anyhow::Result<()> {
+        let _serial = SIGNAL_TEST.lock().await;
+        // 既定の動作でテストのプロセスが終了しないよう、先にハンドラを登録しておく
+        let _hangup = signal(SignalKind::hangup())?;
+        let _terminate = signal(SignalKind::terminate())?;
+        let _user_defined1 = signal(SignalKind::user_defined1())?;
+        let (handle, token, mut reload) = signal_handler();
+
+        // 待ち受けを始めるまでに送ったシグナルは届かないので、届くまで送り直す
+        loop {
+            kill("HUP");
+            if timeout(Duration::from_millis(100), reload.changed())
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+        assert_eq!(*reload.borrow_and_update(), 1);
+        kill("HUP");
+        timeout(Duration::from_secs(5), reload.changed()).await??;
+        assert_eq!(*reload.borrow_and_update(), 2);
+
+        // SIGUSR1は状態を出力するだけで終了しない
+        kill("USR1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!token.is_cancelled());
+
+        kill("TERM");
+        timeout(Duration::from_secs(5), token.cancelled()).await?;
+        handle.await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use futures_util::future::BoxFuture;
+use sqlx::{pool::PoolConnection, Postgres};
+use tracing::{debug, warn};
+
+use super::SqlxPool;
+use crate::lock::{TaskLock, TaskLockGuard};
+
+///
+/// AdvisoryLock
+///   TaskLock for LoopConfig lock which takes pg_try_advisory_lock keyed by the name of the task,
+///   so a looper given the same name on every replica is executed by one of them at each tick.
+///   LoopConfig name is required, without it the key is "looper-{id}" which differs between processes.
+///   The lock is held by one more connection of pg_pool while the task runs.
+///   A replica whose tick comes after the lock is released runs the tick again,
+///   so the clocks of the replicas should be closer than the duration of the task.
+///
+#[derive(Debug, Clone)]
+pub struct AdvisoryLock {
+    pg_pool: SqlxPool,
+}
+
+impl AdvisoryLock {
+    pub fn new(pg_pool: SqlxPool) -> Self {
+        Self { pg_pool }
+    }
+}
+
+impl TaskLock for AdvisoryLock {
+    // 他で持っている場合やエラーの場合はNone
+    fn try_lock<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Option<Box<dyn TaskLockGuard>>> {
+        Box::pin(async move {
+            let mut conn = match self.pg_pool.acquire().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    warn!(error = %err, "advisory lock pool error");
+                    return None;
+                }
+            };
+            let res = sqlx::query_scalar::<_, bool>(
+                "SELECT pg_try_advisory_lock(hashtextextended($1, 0))",
+            )
+            .bind(name)
+            .fetch_one(&mut *conn)
+            .await;
+            match res {
+                Ok(true) => Some(Box::new(AdvisoryLockGuard {
+                    conn: Some(conn),
+                    name: name.to_owned(),
+                }) as Box<dyn TaskLockGuard>),
+                Ok(false) => {
+                    debug!(name, "skip tick locked by another replica");
+                    None
+                }
+                Err(err) => {
+                    warn!(error = %err, "advisory lock error");
+                    None
+                }
+            }
+        })
+    }
+}
+
+// アドバイザリーロックを持った接続
+// 解放しないまま落とされた場合は、接続を切ってロックを解放させる
+struct AdvisoryLockGuard {
+    conn: Option<PoolConnection<Postgres>>,
+    name: String,
+}
+
+impl TaskLockGuard for AdvisoryLockGuard {
+    fn unlock(mut self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let Some(conn) = &mut self.conn else {
+                return;
+            };
+            let res = sqlx::query("SELECT pg_advisory_unlock(hashtextextended($1, 0))")
+                .bind(&self.name)
+                .execute(&mut **conn)
+                .await;
+            match res {
+                // 解放できたらプールに戻す
+                Ok(_) => drop(self.conn.take()),
+                Err(err) => warn!(error = %err, "advisory unlock error"),
+            }
+        })
+    }
+}
+
+impl Drop for AdvisoryLockGuard {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            drop(conn.detach());
+        }
+    }
+}
@@ -1,9 +1,11 @@
-use std::{collections::HashMap, future::Future, hash::Hash, time::Duration};
+use std::{collections::HashMap, future::Future, hash::Hash, sync::Arc, time::Duration};
 
 use chrono::prelude::*;
 
 use thiserror::Error;
 
+use crate::clock::{Clock, SystemClock};
+
 use super::SqlxPool;
 
 #[derive(Error, Debug)]
@@ -17,6 +19,7 @@ pub struct HolderMap<K, V> {
     expire_interval: Duration,
     expire_at: DateTime<Utc>,
     pg_pool: SqlxPool,
+    clock: Arc<dyn Clock>,
 }
 
 impl<K, V> HolderMap<K, V>
@@ -28,11 +31,18 @@ where
         Self {
             map: HashMap::new(),
             expire_interval,
-            expire_at: now.unwrap_or(Utc::now()),
+            // 未指定なら最初のgetで必ず読み込む
+            expire_at: now.unwrap_or(DateTime::<Utc>::MIN_UTC),
             pg_pool,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub async fn get<FutOne, FutAll>(
         &mut self,
         key: &K,
@@ -44,10 +54,10 @@ where
         FutOne: Future<Output = Result<Option<V>, Error>>,
         FutAll: Future<Output = Result<HashMap<K, V>, Error>>,
     {
-        if get_now(now) >= self.expire_at {
+        if get_now(&*self.clock, now) >= self.expire_at {
             let pg_client = self.pg_pool.clone();
             self.map = g(pg_client).await?;
-            self.expire_at = expire_at(now, self.expire_interval);
+            self.expire_at = expire_at(&*self.clock, now, self.expire_interval);
         }
         if let Some(value) = self.map.get(key) {
             return Ok(Some(value.clone()));
@@ -65,6 +75,7 @@ pub struct HolderMapEachExpire<K, V> {
     map: HashMap<K, (V, DateTime<Utc>)>,
     expire_interval: Duration,
     pg_pool: SqlxPool,
+    clock: Arc<dyn Clock>,
 }
 
 impl<K, V> HolderMapEachExpire<K, V>
@@ -77,9 +88,15 @@ where
             map: HashMap::new(),
             expire_interval,
             pg_pool,
+            clock: Arc::new(SystemClock),
         }
     }
 
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub async fn get<FutOne>(
         &mut self,
         key: &K,
@@ -90,7 +107,7 @@ where
         FutOne: Future<Output = Result<Option<V>, Error>>,
     {
         match self.map.get(key) {
-            Some((value, expire_at)) if get_now(now) < *expire_at => {
+            Some((value, expire_at)) if get_now(&*self.clock, now) < *expire_at => {
                 return Ok(Some(value.clone()));
             }
             _ => {}
@@ -101,16 +118,19 @@ where
         };
         self.map.insert(
             key.clone(),
-            (value.clone(), expire_at(now, self.expire_interval)),
+            (
+                value.clone(),
+                expire_at(&*self.clock, now, self.expire_interval),
+            ),
         );
         Ok(Some(value))
     }
 }
 
-fn get_now(now: Option<DateTime<Utc>>) -> DateTime<Utc> {
-    now.unwrap_or(Utc::now())
+fn get_now(clock: &dyn Clock, now: Option<DateTime<Utc>>) -> DateTime<Utc> {
+    now.unwrap_or_else(|| clock.now())
 }
 
-fn expire_at(now: Option<DateTime<Utc>>, interval: Duration) -> DateTime<Utc> {
-    get_now(now) + interval
+fn expire_at(clock: &dyn Clock, now: Option<DateTime<Utc>>, interval: Duration) -> DateTime<Utc> {
+    get_now(clock, now) + interval
 }
@@ -0,0 +1,258 @@
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use chrono::prelude::*;
+use tokio::task::{Id, JoinHandle};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::TaskMetrics;
+use crate::{
+    control::{Control, TaskControl},
+    runner::Panic,
+    LoopState,
+};
+
+///
+/// TaskKind
+///   Looper: made by make_looper
+///   Worker: made by make_worker
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    Looper,
+    Worker,
+}
+
+///
+/// ExitReason
+///   Cancelled: the token was cancelled
+///   Terminated: the task returned LoopState::Terminate
+///   AllTerminated: the task returned LoopState::AllTerminate
+///   ScheduleEnded: the schedule has no more ticks
+///   Panicked: the task panicked and RestartPolicy gave up
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Cancelled,
+    Terminated,
+    AllTerminated,
+    ScheduleEnded,
+    Panicked,
+}
+
+///
+/// TaskStatus
+///   id: unique id in this process
+///   name: name given by LoopConfig, kind and id when not given
+///   kind: looper or worker
+///   running: number of executions in progress
+///   next_tick: next scheduled execution
+///   runs: number of started executions
+///   panics: number of panicked executions
+///   last_started: time the last execution started
+///   last_finished: time the last execution finished
+///   registered: time the loop was made
+///   exit: reason the loop has finished, None while running
+///   paused: paused by TaskControl
+///   metrics: counters and histograms rendered by render_metrics (metrics feature)
+///
+#[derive(Debug, Clone)]
+pub struct TaskStatus {
+    pub id: u64,
+    pub name: String,
+    pub kind: TaskKind,
+    pub running: usize,
+    pub next_tick: Option<DateTime<Utc>>,
+    pub runs: u64,
+    pub panics: u64,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    pub registered: DateTime<Utc>,
+    pub exit: Option<ExitReason>,
+    pub paused: bool,
+    #[cfg(feature = "metrics")]
+    pub metrics: TaskMetrics,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+// spawnしたタスクのIDと状態と操作
+type Entry = (Option<Id>, Weak<Mutex<TaskStatus>>, Weak<Control>);
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+// capture_statusの中でspawnされたタスクのIDと状態
+type Captured = Vec<(Id, Arc<Mutex<TaskStatus>>)>;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Captured>> = const { RefCell::new(None) };
+}
+
+///
+/// Returns the status of every looper and worker alive in this process
+///
+pub fn task_statuses() -> Vec<TaskStatus> {
+    let registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    registry
+        .iter()
+        .filter_map(|(_, it, _)| it.upgrade())
+        .map(|it| lock(&it).clone())
+        .collect()
+}
+
+// tokioのタスクIDから状態を探す、終了したタスクは見つからない
+#[cfg(test)]
+pub(crate) fn find_status(id: Id) -> Option<Arc<Mutex<TaskStatus>>> {
+    let registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    registry
+        .iter()
+        .find(|(it, _, _)| *it == Some(id))
+        .and_then(|(_, it, _)| it.upgrade())
+}
+
+// spawn_functionが返したタスクの状態を、spawnした時点から掴んで返す
+// 登録はWeakなので、spawn後に探すとすぐに終わったタスクの状態は消えている
+pub(crate) fn capture_status<F>(
+    spawn_function: F,
+) -> (JoinHandle<()>, Option<Arc<Mutex<TaskStatus>>>)
+where
+    F: FnOnce() -> JoinHandle<()>,
+{
+    let previous = CAPTURED.replace(Some(vec![]));
+    let handle = spawn_function();
+    let captured = CAPTURED.replace(previous).unwrap_or_default();
+    let status = captured
+        .into_iter()
+        .find(|(id, _)| *id == handle.id())
+        .map(|(_, it)| it);
+    (handle, status)
+}
+
+// tokioのタスクIDから操作を探す
+pub(crate) fn find_control(id: Id) -> Option<TaskControl> {
+    let registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    let (_, status, control) = registry.iter().find(|(it, _, _)| *it == Some(id))?;
+    Some(TaskControl::new(status.upgrade()?, control.upgrade()?))
+}
+
+// ループの状態を記録する
+#[derive(Clone)]
+pub(crate) struct TaskRecorder(Arc<Mutex<TaskStatus>>, Arc<Control>);
+
+impl TaskRecorder {
+    pub(crate) fn register(kind: TaskKind, name: &str, now: DateTime<Utc>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = if name.is_empty() {
+            match kind {
+                TaskKind::Looper => format!("looper-{}", id),
+                TaskKind::Worker => format!("worker-{}", id),
+            }
+        } else {
+            name.to_owned()
+        };
+        let status = Arc::new(Mutex::new(TaskStatus {
+            id,
+            name,
+            kind,
+            running: 0,
+            next_tick: None,
+            runs: 0,
+            panics: 0,
+            last_started: None,
+            last_finished: None,
+            registered: now,
+            exit: None,
+            paused: false,
+            #[cfg(feature = "metrics")]
+            metrics: TaskMetrics::default(),
+        }));
+        let mut registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+        registry.retain(|(_, it, _)| it.strong_count() > 0);
+        let control = Arc::new(Control::new());
+        registry.push((None, Arc::downgrade(&status), Arc::downgrade(&control)));
+        Self(status, control)
+    }
+
+    // spawnしたタスクのIDを紐付ける
+    pub(crate) fn attach(&self, id: Id) {
+        let mut registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(entry) = registry
+            .iter_mut()
+            .find(|(_, it, _)| it.as_ptr() == Arc::as_ptr(&self.0))
+        {
+            entry.0 = Some(id);
+        }
+        CAPTURED.with_borrow_mut(|captured| {
+            if let Some(captured) = captured {
+                captured.push((id, self.0.clone()));
+            }
+        });
+    }
+
+    pub(crate) fn control(&self) -> &Control {
+        &self.1
+    }
+
+    pub(crate) fn name(&self) -> String {
+        lock(&self.0).name.clone()
+    }
+
+    // 実行回数を実行IDとして返す
+    pub(crate) fn start(&self, now: DateTime<Utc>) -> u64 {
+        let mut status = lock(&self.0);
+        status.running += 1;
+        status.runs += 1;
+        status.last_started = Some(now);
+        status.runs
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn finish(
+        &self,
+        res: &Result<LoopState, Panic>,
+        started: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) {
+        let mut status = lock(&self.0);
+        status.running = status.running.saturating_sub(1);
+        if res.is_err() {
+            status.panics += 1;
+        }
+        status.last_finished = Some(now);
+        #[cfg(feature = "metrics")]
+        status.metrics.record(res.as_ref().ok(), started, now);
+    }
+
+    // 並行実行が中断された
+    pub(crate) fn abort(&self, now: DateTime<Utc>) {
+        let mut status = lock(&self.0);
+        status.running = status.running.saturating_sub(1);
+        status.last_finished = Some(now);
+    }
+
+    pub(crate) fn pool_error(&self) {
+        #[cfg(feature = "metrics")]
+        {
+            lock(&self.0).metrics.pool_errors += 1;
+        }
+    }
+
+    pub(crate) fn next_tick(&self, next_tick: DateTime<Utc>) {
+        lock(&self.0).next_tick = Some(next_tick);
+    }
+
+    pub(crate) fn stop(&self, reason: ExitReason) {
+        let mut status = lock(&self.0);
+        status.running = 0;
+        status.next_tick = None;
+        status.exit = Some(reason);
+    }
+}
+
+pub(crate) fn lock(status: &Mutex<TaskStatus>) -> std::sync::MutexGuard<'_, TaskStatus> {
+    status.lock().unwrap_or_else(|err| err.into_inner())
+}
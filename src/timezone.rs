@@ -0,0 +1,130 @@
+use chrono::{prelude::*, MappedLocalTime};
+use chrono_tz::Tz;
+
+///
+/// LoopTimezone
+///   timezone in which the cron schedule of a looper is evaluated
+///   Tz: a zone of chrono-tz, e.g. `Tz::Asia__Tokyo.into()`
+///   Fixed: a fixed offset from UTC, e.g. `FixedOffset::east_opt(9 * 3600).unwrap().into()`
+///   Local: the timezone of the system, `Local.into()`
+///
+#[derive(Debug, Clone, Copy)]
+pub enum LoopTimezone {
+    Tz(Tz),
+    Fixed(FixedOffset),
+    Local,
+}
+
+impl Default for LoopTimezone {
+    fn default() -> Self {
+        LoopTimezone::Tz(Tz::UTC)
+    }
+}
+
+impl From<Tz> for LoopTimezone {
+    fn from(tz: Tz) -> Self {
+        LoopTimezone::Tz(tz)
+    }
+}
+
+impl From<FixedOffset> for LoopTimezone {
+    fn from(offset: FixedOffset) -> Self {
+        LoopTimezone::Fixed(offset)
+    }
+}
+
+impl From<Local> for LoopTimezone {
+    fn from(_: Local) -> Self {
+        LoopTimezone::Local
+    }
+}
+
+// next_tickで使うため、オフセットはFixedOffsetに揃える
+impl TimeZone for LoopTimezone {
+    type Offset = FixedOffset;
+
+    fn from_offset(offset: &FixedOffset) -> Self {
+        LoopTimezone::Fixed(*offset)
+    }
+
+    fn offset_from_local_date(&self, local: &NaiveDate) -> MappedLocalTime<FixedOffset> {
+        match self {
+            LoopTimezone::Tz(tz) => tz.offset_from_local_date(local).map(|it| it.fix()),
+            LoopTimezone::Fixed(offset) => MappedLocalTime::Single(*offset),
+            LoopTimezone::Local => Local.offset_from_local_date(local),
+        }
+    }
+
+    fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> MappedLocalTime<FixedOffset> {
+        match self {
+            LoopTimezone::Tz(tz) => tz.offset_from_local_datetime(local).map(|it| it.fix()),
+            LoopTimezone::Fixed(offset) => MappedLocalTime::Single(*offset),
+            LoopTimezone::Local => Local.offset_from_local_datetime(local),
+        }
+    }
+
+    fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+        match self {
+            LoopTimezone::Tz(tz) => tz.offset_from_utc_date(utc).fix(),
+            LoopTimezone::Fixed(offset) => *offset,
+            LoopTimezone::Local => Local.offset_from_utc_date(utc),
+        }
+    }
+
+    fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+        match self {
+            LoopTimezone::Tz(tz) => tz.offset_from_utc_datetime(utc).fix(),
+            LoopTimezone::Fixed(offset) => *offset,
+            LoopTimezone::Local => Local.offset_from_utc_datetime(utc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use cron::Schedule;
+
+    use super::*;
+    use crate::next_tick;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_loop_timezone() -> anyhow::Result<()> {
+        let schedule = Schedule::from_str("0 0 9 * * *")?;
+        let after = utc("2024-11-13T00:00:00Z");
+
+        // 09:00 JST
+        let tokyo: LoopTimezone = Tz::Asia__Tokyo.into();
+        assert_eq!(
+            next_tick(&schedule, &tokyo, &after),
+            Some(utc("2024-11-14T00:00:00Z"))
+        );
+        let fixed: LoopTimezone = FixedOffset::east_opt(9 * 3600).unwrap().into();
+        assert_eq!(
+            next_tick(&schedule, &fixed, &after),
+            Some(utc("2024-11-14T00:00:00Z"))
+        );
+
+        // システムのタイムゾーンはchrono::Localと同じ結果になる
+        let local: LoopTimezone = Local.into();
+        assert_eq!(
+            next_tick(&schedule, &local, &after),
+            next_tick(&schedule, &Local, &after)
+        );
+
+        // DSTのギャップもTzと同じに扱う
+        let new_york = Tz::America__New_York;
+        let schedule = Schedule::from_str("0 30 2 * * *")?;
+        let after = utc("2024-03-10T05:00:00Z");
+        assert_eq!(
+            next_tick(&schedule, &LoopTimezone::from(new_york), &after),
+            next_tick(&schedule, &new_york, &after)
+        );
+        Ok(())
+    }
+}